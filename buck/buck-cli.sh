@@ -6,7 +6,7 @@ if [ $# -eq 0 ]
 then
     exec ./buck-cli
 else
-    exec ./buck-cli $1
+    exec ./buck-cli "$@"
 fi
 
 return 0
@@ -5,6 +5,13 @@ mkdir -p buck-k5
 cp -a ./buck/. ./buck-k5/
 rm -rf ./buck-k5/bin/mplayer-armhf
 mv ./buck-k5/bin/mplayer-arm ./buck-k5/bin/mplayer
+# mpv isn't built here, it only ships if a binary was dropped in beforehand
+rm -f ./buck-k5/bin/mpv-armhf
+if [ -f ./buck-k5/bin/mpv-arm ]; then
+    mv ./buck-k5/bin/mpv-arm ./buck-k5/bin/mpv
+else
+    echo "buck/bin/mpv-arm not found, packaging without mpv (\"player\": \"mpv\" won't work)"
+fi
 rm ./buck-k5/buck.nmconfig
 rm ./buck-k5/config-sample-kobo.json
 mv ./buck-k5/config-sample-kindle.json ./buck-k5/config.json
\ No newline at end of file
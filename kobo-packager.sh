@@ -14,6 +14,12 @@ cp ../buck/buck-cli ./mnt/onboard/.buck
 cp ../buck/config-sample-kobo.json ./mnt/onboard/.buck/config.json
 cp -a ../buck/assets/. ./mnt/onboard/.buck/assets/
 cp ../buck/bin/mplayer-armhf ./mnt/onboard/.buck/bin/mplayer
+# mpv isn't built here, it only ships if a binary was dropped in beforehand
+if [ -f ../buck/bin/mpv-armhf ]; then
+    cp ../buck/bin/mpv-armhf ./mnt/onboard/.buck/bin/mpv
+else
+    echo "buck/bin/mpv-armhf not found, packaging without mpv (\"player\": \"mpv\" won't work)"
+fi
 cp ../buck/buck.nmconfig ./mnt/onboard/.adds/nm
 cp ../README.md ./mnt/onboard/.buck/
 cp ../LICENSE ./mnt/onboard/.buck/
@@ -0,0 +1,105 @@
+// album_art.rs
+// Album cover lookup and e-ink friendly thumbnails
+
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use id3::{Tag, frame::PictureType};
+use image::{DynamicImage, GrayImage, Luma, imageops::{self, FilterType, colorops::ColorMap}};
+
+use crate::{log, error};
+use crate::read_config::root;
+
+// file names checked (case-insensitively) next to the tracks when there is no embedded art
+const FOLDER_COVER_NAMES: [&str; 4] = ["cover", "folder", "front", "album"];
+const FOLDER_COVER_EXTENSIONS: [&str; 3] = ["jpg", "jpeg", "png"];
+
+pub fn embedded_cover(tag: &Tag) -> Option<&[u8]> {
+    let mut other: Option<&[u8]> = None;
+    for p in tag.pictures() {
+        if p.picture_type == PictureType::CoverFront {
+            return Some(&p.data);
+        } else if p.picture_type == PictureType::Other && other.is_none() {
+            other = Some(&p.data);
+        }
+    }
+    other
+}
+
+pub fn folder_cover(dir: &Path) -> Option<PathBuf> {
+    let entries = fs::read_dir(dir).ok()?;
+    let mut candidates: Vec<PathBuf> = entries.filter_map(|e| e.ok()).map(|e| e.path()).filter(|p| {
+        let stem = p.file_stem().map(|s| s.to_string_lossy().to_lowercase()).unwrap_or_default();
+        let ext = p.extension().map(|s| s.to_string_lossy().to_lowercase()).unwrap_or_default();
+        FOLDER_COVER_NAMES.contains(&stem.as_str()) && FOLDER_COVER_EXTENSIONS.contains(&ext.as_str())
+    }).collect();
+    // prefer names in the order they are listed above
+    candidates.sort_by_key(|p| {
+        let stem = p.file_stem().unwrap().to_string_lossy().to_lowercase();
+        FOLDER_COVER_NAMES.iter().position(|n| *n == stem).unwrap()
+    });
+    candidates.into_iter().next()
+}
+
+pub fn load_cover(tag: Option<&Tag>, track_path: &Path) -> Option<DynamicImage> {
+    if let Some(data) = tag.and_then(embedded_cover) {
+        match image::load_from_memory(data) {
+            Ok(img) => return Some(img),
+            Err(e) => {
+                log!("album_art", "could not decode embedded cover of {}: {:?}", track_path.display(), e);
+            }
+        }
+    }
+    let cover_path = folder_cover(track_path.parent()?)?;
+    match image::open(&cover_path) {
+        Ok(img) => Some(img),
+        Err(e) => {
+            error!("album_art", "could not decode {}: {:?}", cover_path.display(), e);
+            None
+        }
+    }
+}
+
+// maps luma values onto a fixed number of evenly spaced grey levels (like the e-ink panel's own)
+struct GreyLevels {
+    levels: u8
+}
+
+impl GreyLevels {
+    fn step(&self) -> f32 {
+        255.0 / (self.levels.max(2) - 1) as f32
+    }
+}
+
+impl ColorMap for GreyLevels {
+    type Color = Luma<u8>;
+
+    fn index_of(&self, color: &Luma<u8>) -> usize {
+        (color.0[0] as f32 / self.step()).round() as usize
+    }
+    fn lookup(&self, index: usize) -> Option<Luma<u8>> {
+        if index < self.levels.max(2) as usize {
+            Some(Luma([(index as f32 * self.step()).round() as u8]))
+        } else {
+            None
+        }
+    }
+    fn has_lookup(&self) -> bool {
+        true
+    }
+    fn map_color(&self, color: &mut Luma<u8>) {
+        *color = self.lookup(self.index_of(color)).unwrap();
+    }
+}
+
+// square, greyscale, Floyd-Steinberg dithered thumbnail
+pub fn eink_thumbnail(img: &DynamicImage, size: u32, grey_levels: u8) -> GrayImage {
+    let grey = img.to_luma8();
+    let (w, h) = grey.dimensions();
+    let side = w.min(h);
+    let cropped = imageops::crop_imm(&grey, (w - side) / 2, (h - side) / 2, side, side).to_image();
+    let mut thumb = imageops::resize(&cropped, size, size, FilterType::Triangle);
+    imageops::dither(&mut thumb, &GreyLevels { levels: grey_levels });
+    thumb
+}
@@ -19,9 +19,22 @@ fn log(s: &str) -> std::io::Result<()> {
     Ok(())
 }
 
+fn is_track_code(s: &str) -> bool {
+    !s.is_empty() && s.chars().all(|c| c.is_ascii_digit() || c == '.') && s.matches('.').count() <= 1
+}
+
+// passed through to buck as they are, e.g. `buck-cli shuffle albums`
+const COMMANDS: [&str; 7] = ["shuffle", "repeat", "sleep", "speed", "eq", "queue", "failures"];
+
 fn main() -> std::io::Result<()> {
+    let args: Vec<String> = env::args().collect();
+    // on a PC: `buck-cli build <Buck's folder on the mounted device>` hands over to buck's desktop mode
+    if args.get(1).map(|a| a == "build").unwrap_or(false) {
+        let status = Command::new(root("buck")).arg("--build").args(&args[2..]).status()?;
+        exit(status.code().unwrap_or(1));
+    }
     log("[cli] loading...");
-    run(env::args().collect());
+    run(args);
     Ok(())
 }
 
@@ -38,7 +51,20 @@ fn run(args: Vec<String>) -> std::io::Result<()> {
                     stream.shutdown(Shutdown::Both);
                 },
                 _ => {
-                    stream.write_all(b"select")?;
+                    // `buck-cli 123` or `buck-cli 42.3` plays that track right away, anything else opens the selector
+                    if is_track_code(&args[1]) {
+                        stream.write_all(format!("play {}", args[1]).as_bytes())?;
+                    } else if COMMANDS.contains(&args[1].as_str()) {
+                        stream.write_all(args[1..].join(" ").as_bytes())?;
+                        // buck answers some of these (`queue list`), print whatever comes back
+                        stream.shutdown(Shutdown::Write)?;
+                        let mut answer = String::new();
+                        stream.read_to_string(&mut answer)?;
+                        print!("{}", answer);
+                        return Ok(());
+                    } else {
+                        stream.write_all(b"select")?;
+                    }
                     stream.flush();
                     stream.shutdown(Shutdown::Both);
                 }
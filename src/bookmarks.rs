@@ -0,0 +1,70 @@
+// bookmarks.rs
+// Resume positions for audiobooks and other long files, kept per file
+
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::{log, error};
+use crate::read_config::{root, BuckConfig};
+use crate::utils::atomic_write::write_atomic;
+
+const BOOKMARKS_FILE: &str = "bookmarks.json";
+// closer than this to either end, there is nothing worth coming back to
+const MIN_POSITION_SECS: f32 = 10.0;
+const FINISHED_SECS: f32 = 30.0;
+
+pub struct Bookmarks {
+    // keyed by path on the device
+    positions: HashMap<PathBuf, f32>,
+    min_length: f32,
+    spoken_word_dirs: Vec<PathBuf>
+}
+
+impl Bookmarks {
+    pub fn load(config: &BuckConfig) -> Bookmarks {
+        let positions = match fs::read_to_string(root(BOOKMARKS_FILE)) {
+            Ok(s) => serde_json::from_str(&s).unwrap_or_else(|e| {
+                error!("bookmarks", "ignoring unreadable bookmarks: {:?}", e);
+                HashMap::new()
+            }),
+            Err(_) => HashMap::new()
+        };
+        Bookmarks {
+            positions,
+            min_length: config.bookmarks.min_length_mins as f32 * 60.0,
+            spoken_word_dirs: config.bookmarks.spoken_word_dirs.iter().map(PathBuf::from).collect()
+        }
+    }
+    fn save(&self) {
+        let result = serde_json::to_vec(&self.positions).map_err(std::io::Error::from).and_then(|data| write_atomic(&root(BOOKMARKS_FILE), &data));
+        if let Err(e) = result {
+            error!("bookmarks", "could not write bookmarks: {:?}", e);
+        }
+    }
+    fn applies_to(&self, path: &Path, length: f32) -> bool {
+        (self.min_length > 0.0 && length >= self.min_length) || self.spoken_word_dirs.iter().any(|d| path.starts_with(d))
+    }
+    // the track is being left at position, by Next, Previous or picking another one
+    pub fn leave(&mut self, path: &Path, position: f32, length: f32) {
+        if !self.applies_to(path, length) {
+            return;
+        }
+        if position < MIN_POSITION_SECS || (length > 0.0 && position > length - FINISHED_SECS) {
+            self.finished(path);
+            return;
+        }
+        log!("bookmarks", "{} left at {}", path.display(), position);
+        self.positions.insert(path.to_path_buf(), position);
+        self.save();
+    }
+    pub fn finished(&mut self, path: &Path) {
+        if self.positions.remove(path).is_some() {
+            self.save();
+        }
+    }
+    pub fn position(&self, path: &Path) -> Option<f32> {
+        self.positions.get(path).copied()
+    }
+}
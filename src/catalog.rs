@@ -0,0 +1,443 @@
+// catalog.rs
+// Music directory scanner and catalog cache
+
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Write, Read};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use id3::{Tag, TagLike};
+use serde::{Serialize, Deserialize};
+use symphonia::core::formats::FormatOptions;
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::{MetadataOptions, StandardTagKey, Tag as MetaTag};
+use symphonia::core::probe::Hint;
+use walkdir::WalkDir;
+
+use crate::{Track, log, error, result};
+use crate::process_runner::quick_write;
+use crate::read_config::{root, BuckConfig};
+use crate::replay_gain::ReplayGain;
+use crate::utils::atomic_write::write_atomic;
+
+const CATALOG_CACHE_FILE: &str = "catalog.json";
+pub const AUDIO_EXTENSIONS: [&str; 5] = ["mp3", "wav", "flac", "ogg", "oga"];
+
+// everything read out of a file while scanning, kept so unchanged files are not opened again
+#[derive(Serialize, Deserialize, Clone)]
+struct CatalogEntry {
+    path: PathBuf,
+    mtime: u64,
+    size: u64,
+    title: String,
+    artist: String,
+    album: String,
+    track: u32,
+    disc: u32,
+    year: i32,
+    duration: f32,
+    // when the file first showed up in the catalog (seconds since the epoch)
+    #[serde(default)]
+    first_seen: u64,
+    // None for entries cached before gains were read, they get filled in on the next scan
+    #[serde(default)]
+    replay_gain: Option<ReplayGain>,
+    // the player gave up on it, cleared when the file changes and is read again
+    #[serde(default)]
+    unplayable: bool,
+    // number within the album, handed out once like album numbers (0 until it has one)
+    #[serde(default)]
+    album_track: u32,
+    // the album artist tag, "" without one; None for entries cached before it was read
+    #[serde(default)]
+    album_artist: Option<String>
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct CatalogCache {
+    entries: Vec<CatalogEntry>,
+    // album key -> album number, never reassigned or reused once handed out
+    #[serde(default)]
+    album_numbers: HashMap<String, u32>
+}
+
+// albums are told apart by album artist and name, so two "Greatest Hits" don't end up as one
+fn album_key(album_artist: &str, album: &str) -> String {
+    format!("{}\t{}", album_artist, album)
+}
+
+// the album's number, handing out the next one the first time it's seen
+fn album_number_for(album_numbers: &mut HashMap<String, u32>, key: &str) -> u32 {
+    if let Some(&n) = album_numbers.get(key) {
+        return n;
+    }
+    let next_number = album_numbers.values().max().map(|n| n + 1).unwrap_or(1);
+    album_numbers.insert(key.to_string(), next_number);
+    next_number
+}
+
+impl CatalogCache {
+    fn load(path: &Path) -> CatalogCache {
+        let mut s = String::new();
+        match File::open(path).and_then(|mut f| f.read_to_string(&mut s)) {
+            Ok(_) => match serde_json::from_str(&s) {
+                Ok(cache) => cache,
+                Err(e) => {
+                    error!("catalog", "ignoring unreadable catalog cache: {:?}", e);
+                    CatalogCache::default()
+                }
+            },
+            Err(_) => CatalogCache::default()
+        }
+    }
+    fn save(&self, path: &Path) {
+        let result = serde_json::to_vec(self).map_err(std::io::Error::from).and_then(|data| write_atomic(path, &data));
+        if let Err(e) = result {
+            error!("catalog", "could not write catalog cache: {:?}", e);
+        }
+    }
+}
+
+// device paths (as written in config.json and the cache) <-> paths the files can be opened at from this process
+pub struct PathMap {
+    device: PathBuf,
+    local: PathBuf
+}
+
+impl PathMap {
+    pub fn new(device: PathBuf, local: PathBuf) -> PathMap {
+        PathMap { device, local }
+    }
+    // running on the device itself
+    pub fn identity() -> PathMap {
+        PathMap { device: PathBuf::from("/"), local: PathBuf::from("/") }
+    }
+    pub fn to_local(&self, p: &Path) -> PathBuf {
+        match p.strip_prefix(&self.device) {
+            Ok(rest) => self.local.join(rest),
+            Err(_) => p.to_path_buf()
+        }
+    }
+    pub fn to_device(&self, p: &Path) -> PathBuf {
+        match p.strip_prefix(&self.local) {
+            Ok(rest) => self.device.join(rest),
+            Err(_) => p.to_path_buf()
+        }
+    }
+}
+
+// FAT stores local time, so a PC and the device can see the same file's mtime shifted by whole quarter hours
+fn same_mtime(a: u64, b: u64) -> bool {
+    let diff = a.abs_diff(b);
+    diff == 0 || (diff <= 24 * 3600 && diff % 900 == 0)
+}
+
+struct Album {
+    artists: HashMap<String, usize>,
+    tracks: Vec<Track>
+}
+impl Album {
+    pub fn new() -> Album {
+        Album { artists: HashMap::new(), tracks: Vec::new() }
+    }
+    pub fn push(&mut self, t: Track) {
+        *self.artists.entry(t.artist.clone()).or_insert(0) += 1;
+        self.tracks.push(t);
+    }
+    pub fn tracks(&mut self) -> &mut Vec<Track> {
+        &mut self.tracks
+    }
+    pub fn artist(&self) -> String {
+        if let Some(a) = self.artists.iter().max_by(|x, y| x.1.cmp(y.1)) {
+            a.0.clone()
+        } else {
+            String::new()
+        }
+    }
+}
+
+fn is_hidden(entry: &walkdir::DirEntry) -> bool {
+    entry.file_name()
+         .to_str()
+         .map(|s| s.starts_with("."))
+         .unwrap_or(false)
+}
+
+// length of the file in seconds, from the container header if it has one, otherwise by walking the packets (no decoding)
+pub fn probe_duration(path: &Path) -> Option<f32> {
+    let file = File::open(path).ok()?;
+    let mss = MediaSourceStream::new(Box::new(file), Default::default());
+    let mut hint = Hint::new();
+    if let Some(ext) = path.extension().and_then(|e| e.to_str()) {
+        hint.with_extension(ext);
+    }
+    let probed = symphonia::default::get_probe().format(&hint, mss, &FormatOptions::default(), &MetadataOptions::default()).ok()?;
+    let mut format = probed.format;
+    let track = format.default_track()?;
+    let track_id = track.id;
+    let params = track.codec_params.clone();
+    let time_base = params.time_base.or_else(|| params.sample_rate.map(|r| symphonia::core::units::TimeBase::new(1, r)))?;
+    let n_frames = match params.n_frames {
+        Some(n) => n,
+        None => {
+            let mut n: u64 = 0;
+            while let Ok(packet) = format.next_packet() {
+                if packet.track_id() == track_id {
+                    n += packet.dur;
+                }
+            }
+            n
+        }
+    };
+    let time = time_base.calc_time(n_frames);
+    Some(time.seconds as f32 + time.frac as f32)
+}
+
+// Vorbis comments and the like, for files without an ID3 tag (FLAC, Ogg)
+fn probe_tags(path: &Path) -> Vec<MetaTag> {
+    let file = match File::open(path) {
+        Ok(f) => f,
+        Err(_) => return Vec::new()
+    };
+    let mss = MediaSourceStream::new(Box::new(file), Default::default());
+    let mut hint = Hint::new();
+    if let Some(ext) = path.extension().and_then(|e| e.to_str()) {
+        hint.with_extension(ext);
+    }
+    let mut probed = match symphonia::default::get_probe().format(&hint, mss, &FormatOptions::default(), &MetadataOptions::default()) {
+        Ok(p) => p,
+        Err(_) => return Vec::new()
+    };
+    if let Some(rev) = probed.format.metadata().current() {
+        return rev.tags().to_vec();
+    }
+    probed.metadata.get().and_then(|m| m.current().map(|rev| rev.tags().to_vec())).unwrap_or_default()
+}
+
+// TXXX frames in ID3, otherwise Vorbis comments and the like
+fn read_replay_gain(path: &Path) -> ReplayGain {
+    let mut gain = ReplayGain::default();
+    match Tag::read_from_path(path) {
+        Ok(tag) => {
+            for t in tag.extended_texts() {
+                gain.read_tag(&t.description, &t.value);
+            }
+        },
+        Err(_) => {
+            for t in probe_tags(path) {
+                gain.read_tag(&t.key, &t.value.to_string());
+            }
+        }
+    }
+    gain
+}
+
+// TPE2 in ID3, otherwise the ALBUMARTIST comment and the like
+fn read_album_artist(path: &Path) -> String {
+    match Tag::read_from_path(path) {
+        Ok(tag) => tag.album_artist().map(String::from).unwrap_or_default(),
+        Err(_) => probe_tags(path).into_iter().find(|t| t.std_key == Some(StandardTagKey::AlbumArtist)).map(|t| t.value.to_string()).unwrap_or_default()
+    }
+}
+
+// 0 when the file can't be probed, such entries get probed again on the next scan instead of keeping the 0
+fn read_duration(path: &Path) -> f32 {
+    probe_duration(path).unwrap_or_else(|| {
+        error!("catalog", "could not determine the length of {}", path.display());
+        0.0
+    })
+}
+
+// "3/12" -> 3
+fn leading_number<T: std::str::FromStr>(s: &str) -> Option<T> {
+    s.split('/').next().and_then(|n| n.trim().parse().ok())
+}
+
+// "1999-05-01" -> 1999, dates can be down to the day
+fn leading_year(s: &str) -> Option<i32> {
+    s.trim().get(..4).and_then(|y| y.parse().ok())
+}
+
+fn read_entry(path: &Path, mtime: u64, size: u64) -> CatalogEntry {
+    //default values in case tag is not available
+    let mut title = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or("".into());
+    let mut artist = String::new();
+    let mut album = path.parent().unwrap().file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or("".into());
+    let mut track: u32 = 0;
+    let mut disc: u32 = 1;
+    let mut year: i32 = 0;
+    //read tag
+    if let Ok(tag) = Tag::read_from_path(path) {
+        if let Some(id3artist) = tag.artist() {
+            artist = String::from(id3artist);
+        }
+        if let Some(id3title) = tag.title() {
+            title = String::from(id3title);
+        }
+        if let Some(id3album) = tag.album() {
+            album = String::from(id3album);
+        }
+        if let Some(id3track) = tag.track() {
+            track = id3track;
+        }
+        if let Some(id3disc) = tag.disc() {
+            disc = id3disc;
+        }
+        if let Some(id3year) = tag.year() {
+            year = id3year;
+        }
+        if let Some(a) = tag.get("TDOR").or_else(|| tag.get("TORY")) {
+            year = a.content().text().and_then(leading_year).unwrap_or(year);
+        }
+    } else {
+        for tag in probe_tags(path) {
+            let value = tag.value.to_string();
+            match tag.std_key {
+                Some(StandardTagKey::TrackTitle) => title = value,
+                Some(StandardTagKey::Artist) => artist = value,
+                Some(StandardTagKey::Album) => album = value,
+                Some(StandardTagKey::TrackNumber) => track = leading_number(&value).unwrap_or(track),
+                Some(StandardTagKey::DiscNumber) => disc = leading_number(&value).unwrap_or(disc),
+                Some(StandardTagKey::Date) | Some(StandardTagKey::OriginalDate) => year = leading_year(&value).unwrap_or(year),
+                _ => {}
+            }
+        }
+    }
+    let duration = read_duration(path);
+    CatalogEntry { path: path.to_path_buf(), mtime, size, title, artist, album, track, disc, year, duration, first_seen: 0, replay_gain: Some(read_replay_gain(path)), unplayable: false, album_track: 0, album_artist: Some(read_album_artist(path)) }
+}
+
+// called from the player, whose paths are the device's own
+pub fn mark_unplayable(path: &Path) {
+    let cache_path = root(CATALOG_CACHE_FILE);
+    let mut cache = CatalogCache::load(&cache_path);
+    if let Some(entry) = cache.entries.iter_mut().find(|e| e.path == path) {
+        entry.unplayable = true;
+        cache.save(&cache_path);
+    }
+}
+
+pub fn scan(config: &BuckConfig, paths: &PathMap) -> Vec<Track> {
+    //check catalog
+    log!("catalog", "reading tracks...");
+    let cache_path = root(CATALOG_CACHE_FILE);
+    let old_cache = CatalogCache::load(&cache_path);
+    let first_scan = old_cache.entries.is_empty();
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let mut cached: HashMap<PathBuf, CatalogEntry> = old_cache.entries.into_iter().map(|e| (e.path.clone(), e)).collect();
+    let mut new_cache = CatalogCache { entries: Vec::new(), album_numbers: old_cache.album_numbers };
+    let mut albums: HashMap<String, Album> = HashMap::new();
+    let mut albums_order: Vec<(String, i32, String)> = Vec::new();
+
+    log!("catalog", "opening music directories...");
+    quick_write(1, "* Cataloging...");
+    for music_dir in &config.music_dirs {
+        for entry in WalkDir::new(paths.to_local(Path::new(music_dir))).into_iter().filter_entry(|e| !is_hidden(e)) {
+            let entry = result!(entry);
+            match entry.path().extension() {
+                None => continue,
+                Some(ext) => if !AUDIO_EXTENSIONS.iter().any(|a| ext.eq_ignore_ascii_case(a)) { continue; }
+            }
+            let metadata = result!(entry.metadata());
+            let mtime = metadata.modified().ok().and_then(|t| t.duration_since(UNIX_EPOCH).ok()).map(|d| d.as_secs()).unwrap_or(0);
+            let size = metadata.len();
+            let device_path = paths.to_device(entry.path());
+            let catalog_entry = match cached.remove(&device_path) {
+                Some(c) if same_mtime(c.mtime, mtime) && c.size == size => {
+                    let duration = if c.duration > 0.0 { c.duration } else { read_duration(entry.path()) };
+                    let replay_gain = c.replay_gain.or_else(|| Some(read_replay_gain(entry.path())));
+                    let album_artist = c.album_artist.or_else(|| Some(read_album_artist(entry.path())));
+                    CatalogEntry { duration, replay_gain, album_artist, ..c }
+                },
+                Some(c) => CatalogEntry { path: device_path, first_seen: c.first_seen, album_track: c.album_track, ..read_entry(entry.path(), mtime, size) },
+                // on the very first scan everything is "new", so fall back to the file's own date
+                None => CatalogEntry { path: device_path, first_seen: if first_scan { mtime } else { now }, ..read_entry(entry.path(), mtime, size) }
+            };
+            let c = catalog_entry.clone();
+            new_cache.entries.push(catalog_entry);
+            let key = album_key(c.album_artist.as_deref().unwrap_or(""), &c.album);
+            if !albums.contains_key(&key) {
+                albums.insert(key.clone(), Album::new());
+            }
+            albums_order.push((c.artist.clone(), c.year, key.clone()));
+            albums.get_mut(&key).unwrap().push(Track { path: paths.to_local(&c.path), title: c.title, artist: c.artist, album: c.album, track: c.track, disc: c.disc, year: c.year, album_artist: String::new(), duration: c.duration, album_number: 0, album_track: c.album_track, added: if c.first_seen > 0 { c.first_seen } else { c.mtime }, replay_gain: c.replay_gain.unwrap_or_default(), unplayable: c.unplayable });
+        }
+    }
+
+    log!("catalog", "sorting...");
+    // sort catalog
+    albums_order.sort_by(|a, b| {
+        if a.0.eq_ignore_ascii_case(&b.0) {
+            b.1.partial_cmp(&a.1).unwrap()
+        } else {
+            a.0.partial_cmp(&b.0).unwrap()
+        }
+    });
+
+    let mut tracks: Vec<Track> = Vec::new();
+    let mut album_tracks: HashMap<PathBuf, u32> = HashMap::new();
+
+    for a in albums_order {
+        let album = albums.get_mut(&a.2).unwrap();
+        album.tracks().sort_by(|a, b| {
+            if a.disc == b.disc {
+                a.track.partial_cmp(&b.track).unwrap()
+            } else {
+                a.disc.partial_cmp(&b.disc).unwrap()
+            }
+        });
+        let album_artist = album.artist();
+        if let Some(first_track) = album.tracks().get_mut(0) {
+            first_track.album_artist = album_artist;
+        }
+        let album_number = album_number_for(&mut new_cache.album_numbers, &a.2);
+        // tracks keep the number they were given, new ones (or ones retagged into a clash) go after the highest
+        let mut next_track = album.tracks().iter().map(|t| t.album_track).max().unwrap_or(0) + 1;
+        let mut taken: HashSet<u32> = HashSet::new();
+        for t in album.tracks().iter_mut() {
+            if t.album_track == 0 || !taken.insert(t.album_track) {
+                t.album_track = next_track;
+                taken.insert(next_track);
+                next_track += 1;
+            }
+            t.album_number = album_number;
+            album_tracks.insert(paths.to_device(&t.path), t.album_track);
+        }
+        tracks.append(album.tracks());
+    }
+    for e in new_cache.entries.iter_mut() {
+        e.album_track = album_tracks.get(&e.path).copied().unwrap_or(e.album_track);
+    }
+    new_cache.save(&cache_path);
+
+    tracks
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{album_key, album_number_for, leading_year};
+
+    #[test]
+    fn original_dates_give_their_year() {
+        assert_eq!(leading_year("1999"), Some(1999));
+        assert_eq!(leading_year("1999-05-01"), Some(1999));
+        assert_eq!(leading_year("May 1999"), None);
+        assert_eq!(leading_year(""), None);
+    }
+
+    #[test]
+    fn albums_of_the_same_name_get_numbers_of_their_own() {
+        let mut numbers = HashMap::new();
+        let first = album_number_for(&mut numbers, &album_key("Band", "Greatest Hits"));
+        let second = album_number_for(&mut numbers, &album_key("Singer", "Greatest Hits"));
+        assert_ne!(first, second);
+        // and keep them on the next scan, whichever comes first
+        assert_eq!(album_number_for(&mut numbers, &album_key("Singer", "Greatest Hits")), second);
+        assert_eq!(album_number_for(&mut numbers, &album_key("Band", "Greatest Hits")), first);
+        // one without an album artist tag is another album again
+        assert!(album_number_for(&mut numbers, &album_key("", "Greatest Hits")) > second);
+    }
+}
@@ -0,0 +1,94 @@
+// desktop_build.rs
+// Builds the catalog cache and T.O.C. on a PC, for a device mounted over USB
+
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Component, Path, PathBuf};
+use std::process::exit;
+
+use crate::{toc, html_toc, text_fonts, catalog::{self, PathMap}, log, error};
+use crate::read_config::{self, root, set_root};
+use crate::history::PlayHistory;
+
+const USAGE: &str = "usage: buck --build <Buck's folder on the mounted device> [--mount <device path>=<local path>]
+
+  e.g. buck --build /media/me/KOBOeReader/.buck
+       buck --build /media/me/Kindle/buck --mount /mnt/us=/media/me/Kindle";
+
+fn usage_and_exit() -> ! {
+    eprintln!("{}", USAGE);
+    exit(2);
+}
+
+// "/mnt/onboard/music" -> "/mnt/onboard", the mount point of the device's user storage
+fn storage_prefix(p: &Path) -> PathBuf {
+    p.components().take_while(|c| matches!(c, Component::RootDir | Component::Normal(_))).take(3).collect()
+}
+
+pub fn run(args: &[String]) {
+    let mut buck_dir: Option<PathBuf> = None;
+    let mut mount: Option<(PathBuf, PathBuf)> = None;
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--mount" => {
+                i += 1;
+                match args.get(i).and_then(|m| m.split_once('=')) {
+                    Some((device, local)) => mount = Some((PathBuf::from(device), PathBuf::from(local))),
+                    None => usage_and_exit()
+                }
+            },
+            a if buck_dir.is_none() && !a.starts_with("--") => buck_dir = Some(PathBuf::from(a)),
+            _ => usage_and_exit()
+        }
+        i += 1;
+    }
+    let buck_dir = match buck_dir.and_then(|d| fs::canonicalize(d).ok()) {
+        Some(d) => d,
+        None => usage_and_exit()
+    };
+    if !buck_dir.join("config.json").exists() {
+        eprintln!("no config.json in {}, is that Buck's folder on the device?", buck_dir.display());
+        exit(1);
+    }
+
+    // config, fonts, catalog cache and fingerprint all live in the device's Buck folder
+    set_root(buck_dir.clone());
+    let config = read_config::read_config();
+    text_fonts::init(&config);
+
+    let (device, local) = mount.unwrap_or_else(|| {
+        let device = storage_prefix(Path::new(config.music_dirs.first().unwrap_or(&config.documents_dir)));
+        (device, buck_dir.parent().unwrap().to_path_buf())
+    });
+    println!("[*] {} on the device is {} here", device.display(), local.display());
+    log!("desktop_build", "mapping {} to {}", device.display(), local.display());
+    let paths = PathMap::new(device, local);
+
+    println!("[*] scanning music...");
+    let tracks = catalog::scan(&config, &paths);
+    println!("[*] {} tracks, catalog written to {}", tracks.len(), root("catalog.json").display());
+    if tracks.is_empty() {
+        eprintln!("no music found in {:?}", config.music_dirs);
+        exit(1);
+    }
+
+    let toc_sections = toc::TocSections::new(&tracks, &config, &PlayHistory::load_mapped(&paths));
+    let documents_dir = paths.to_local(Path::new(&config.documents_dir));
+    if let Err(e) = fs::create_dir_all(&documents_dir) {
+        error!("desktop_build", "could not create {}: {:?}", documents_dir.display(), e);
+    }
+
+    if config.http.enabled {
+        let html_path = documents_dir.join(html_toc::HTML_FILE_NAME);
+        html_toc::genhtml(&tracks, &toc_sections, &html_path, &config);
+        println!("[*] library index written to {}", html_path.display());
+    }
+
+    let toc_path = documents_dir.join(toc::TOC_FILE_NAME);
+    if toc::is_current(&tracks, &toc_sections, &toc_path, &config) {
+        println!("[*] {} is already up to date", toc_path.display());
+        return;
+    }
+    toc::gentoc(&tracks, &toc_sections, toc_path, &config);
+}
@@ -0,0 +1,121 @@
+// equalizer.rs
+// 10-band equalizer presets, and the filter bank the native backend runs them through
+
+use std::f32::consts::PI;
+
+use serde::{Serialize, Deserialize};
+
+// gains in dB for the octave bands of BAND_FREQS, the same ones mplayer's equalizer uses
+pub type Bands = [f32; 10];
+
+pub const BAND_FREQS: Bands = [31.25, 62.5, 125.0, 250.0, 500.0, 1000.0, 2000.0, 4000.0, 8000.0, 16000.0];
+pub const MAX_GAIN_DB: f32 = 12.0;
+pub const FLAT: Bands = [0.0; 10];
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct EqPreset {
+    pub name: String,
+    pub bands: Bands
+}
+
+// the built-in presets, followed by the user's own from the config
+pub fn presets(user: &[EqPreset]) -> Vec<EqPreset> {
+    let builtin = [
+        ("Flat", FLAT),
+        ("Bass Boost", [6.0, 5.0, 4.0, 2.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0]),
+        ("Spoken Word", [-6.0, -4.0, -2.0, 0.0, 2.0, 3.0, 4.0, 3.0, 1.0, 0.0]),
+        ("Treble Cut", [0.0, 0.0, 0.0, 0.0, 0.0, 0.0, -2.0, -4.0, -6.0, -8.0])
+    ];
+    let mut all: Vec<EqPreset> = builtin.iter().map(|(name, bands)| EqPreset { name: name.to_string(), bands: *bands }).collect();
+    for p in user {
+        let bands = p.bands.map(|g| g.max(-MAX_GAIN_DB).min(MAX_GAIN_DB));
+        match all.iter_mut().find(|a| a.name.eq_ignore_ascii_case(&p.name)) {
+            Some(existing) => existing.bands = bands,
+            None => all.push(EqPreset { name: p.name.clone(), bands })
+        }
+    }
+    all
+}
+
+// preset names are matched without regard to case
+pub fn find<'a>(presets: &'a [EqPreset], name: &str) -> Option<&'a EqPreset> {
+    presets.iter().find(|p| p.name.eq_ignore_ascii_case(name.trim()))
+}
+
+pub fn is_flat(bands: &Bands) -> bool {
+    bands.iter().all(|g| *g == 0.0)
+}
+
+// peaking biquad, coefficients from the Audio EQ Cookbook, normalised by a0
+#[derive(Clone, Copy)]
+struct Biquad {
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32
+}
+
+impl Biquad {
+    fn peaking(freq: f32, gain_db: f32, rate: u32) -> Biquad {
+        // one octave wide
+        let q = 1.41;
+        let a = 10f32.powf(gain_db / 40.0);
+        let w0 = 2.0 * PI * freq / rate as f32;
+        let alpha = w0.sin() / (2.0 * q);
+        let cos_w0 = w0.cos();
+        let a0 = 1.0 + alpha / a;
+        Biquad {
+            b0: (1.0 + alpha * a) / a0,
+            b1: -2.0 * cos_w0 / a0,
+            b2: (1.0 - alpha * a) / a0,
+            a1: -2.0 * cos_w0 / a0,
+            a2: (1.0 - alpha / a) / a0
+        }
+    }
+}
+
+pub struct Filter {
+    bands: Bands,
+    format: Option<(u32, usize)>,
+    stages: Vec<Biquad>,
+    // x1, x2, y1, y2 for each stage and channel
+    history: Vec<[f32; 4]>
+}
+
+impl Filter {
+    pub fn new(bands: Bands) -> Filter {
+        Filter { bands, format: None, stages: Vec::new(), history: Vec::new() }
+    }
+    pub fn set_bands(&mut self, bands: Bands) {
+        self.bands = bands;
+        // rebuilt on the next block, history and all
+        self.format = None;
+    }
+    // runs interleaved samples through every band in place
+    pub fn process(&mut self, samples: &mut [f32], rate: u32, channels: usize) {
+        if is_flat(&self.bands) || channels == 0 {
+            return;
+        }
+        if self.format != Some((rate, channels)) {
+            self.stages = BAND_FREQS.iter().zip(self.bands.iter())
+                .filter(|(f, g)| **g != 0.0 && **f < rate as f32 * 0.45)
+                .map(|(f, g)| Biquad::peaking(*f, *g, rate))
+                .collect();
+            self.history = vec![[0.0; 4]; self.stages.len() * channels];
+            self.format = Some((rate, channels));
+        }
+        for frame in samples.chunks_mut(channels) {
+            for (c, sample) in frame.iter_mut().enumerate() {
+                let mut x = *sample;
+                for (s, stage) in self.stages.iter().enumerate() {
+                    let h = &mut self.history[s * channels + c];
+                    let y = stage.b0 * x + stage.b1 * h[0] + stage.b2 * h[1] - stage.a1 * h[2] - stage.a2 * h[3];
+                    *h = [x, h[0], y, h[2]];
+                    x = y;
+                }
+                *sample = x;
+            }
+        }
+    }
+}
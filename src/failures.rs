@@ -0,0 +1,51 @@
+// failures.rs
+// Tracks that would not play, skipped after a few tries and written down for cleaning up later
+
+use std::collections::BTreeSet;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{Track, log, error, catalog};
+use crate::read_config::root;
+
+// one line per track given up on: seconds since the epoch, path, attempts
+const FAILURES_LOG: &str = "failures.log";
+// loads of the same file before it is skipped
+pub const MAX_ATTEMPTS: u32 = 3;
+
+pub struct Failures {
+    // marked in the catalog, by this run or an earlier one
+    unplayable: BTreeSet<PathBuf>
+}
+
+impl Failures {
+    pub fn new(tracks: &[Track]) -> Failures {
+        Failures { unplayable: tracks.iter().filter(|t| t.unplayable).map(|t| t.path.clone()).collect() }
+    }
+    pub fn is_unplayable(&self, path: &Path) -> bool {
+        self.unplayable.contains(path)
+    }
+    pub fn give_up(&mut self, path: &Path, attempts: u32) {
+        error!("failures", "giving up on {} after {} attempts", path.display(), attempts);
+        self.unplayable.insert(path.to_path_buf());
+        catalog::mark_unplayable(path);
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let line = format!("{}\t{}\t{} attempts\n", now, path.display(), attempts);
+        let written = OpenOptions::new().append(true).create(true).open(root(FAILURES_LOG)).and_then(|mut f| f.write_all(line.as_bytes()));
+        if let Err(e) = written {
+            error!("failures", "could not write {}: {:?}", FAILURES_LOG, e);
+        }
+    }
+    // for buck-cli, every track currently skipped
+    pub fn report(&self) -> String {
+        log!("failures", "{} unplayable tracks", self.unplayable.len());
+        if self.unplayable.is_empty() {
+            return String::from("no unplayable tracks");
+        }
+        let mut lines: Vec<String> = vec![format!("{} unplayable tracks (history in {}):", self.unplayable.len(), root(FAILURES_LOG).display())];
+        lines.extend(self.unplayable.iter().map(|p| p.display().to_string()));
+        lines.join("\n")
+    }
+}
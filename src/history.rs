@@ -0,0 +1,64 @@
+// history.rs
+// Play counts, used for the "Most played" section of the T.O.C.
+
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use serde::{Serialize, Deserialize};
+
+use crate::{log, error};
+use crate::catalog::PathMap;
+use crate::read_config::root;
+use crate::utils::atomic_write::write_atomic;
+
+const HISTORY_FILE: &str = "history.json";
+
+// a play counts once this much of the track (or PLAY_COUNT_MAX_SECS) has been listened to
+const PLAY_COUNT_FRACTION: f32 = 0.5;
+const PLAY_COUNT_MAX_SECS: f32 = 240.0;
+
+// keyed by path on the device
+#[derive(Serialize, Deserialize, Default)]
+pub struct PlayHistory {
+    play_counts: HashMap<PathBuf, u32>
+}
+
+impl PlayHistory {
+    pub fn load() -> PlayHistory {
+        match fs::read_to_string(root(HISTORY_FILE)) {
+            Ok(s) => match serde_json::from_str(&s) {
+                Ok(h) => h,
+                Err(e) => {
+                    error!("history", "ignoring unreadable play history: {:?}", e);
+                    PlayHistory::default()
+                }
+            },
+            Err(_) => PlayHistory::default()
+        }
+    }
+    // with the paths rewritten to where this process sees the files (see desktop_build)
+    pub fn load_mapped(paths: &PathMap) -> PlayHistory {
+        let h = PlayHistory::load();
+        PlayHistory { play_counts: h.play_counts.into_iter().map(|(p, c)| (paths.to_local(&p), c)).collect() }
+    }
+    fn save(&self) {
+        let result = serde_json::to_vec(self).map_err(std::io::Error::from).and_then(|data| write_atomic(&root(HISTORY_FILE), &data));
+        if let Err(e) = result {
+            error!("history", "could not write play history: {:?}", e);
+        }
+    }
+    pub fn count(&self, track_path: &Path) -> u32 {
+        self.play_counts.get(track_path).copied().unwrap_or(0)
+    }
+    pub fn record_play(&mut self, track_path: &Path) {
+        *self.play_counts.entry(track_path.to_path_buf()).or_insert(0) += 1;
+        log!("history", "{} played {} times", track_path.display(), self.count(track_path));
+        self.save();
+    }
+}
+
+pub fn listened_enough(time_pos: f32, length: f32) -> bool {
+    time_pos >= PLAY_COUNT_MAX_SECS || (length > 0.0 && time_pos >= length * PLAY_COUNT_FRACTION)
+}
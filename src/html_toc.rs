@@ -0,0 +1,102 @@
+// html_toc.rs
+// Library index for the device's web browser, every entry links to the local control endpoint
+
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+
+use crate::{Track, log, error};
+use crate::read_config::{root, BuckConfig};
+use crate::toc::{album_summary, format_duration, TocSections};
+use crate::track_code::TrackCode;
+use crate::utils::atomic_write::write_atomic;
+
+pub const HTML_FILE_NAME: &str = "Buck - Library.html";
+
+const STYLE: &str = "body{font-family:serif;font-size:1.4em;margin:1em;color:#000;background:#fff}\
+a{color:#000;text-decoration:none}\
+h2{margin:1.4em 0 0 0;font-style:italic}\
+.info{color:#555;margin:0 0 .4em 0}\
+table{width:100%;border-collapse:collapse}\
+td{padding:.45em .2em;border-bottom:1px solid #ccc}\
+.code,.len{color:#777;white-space:nowrap}\
+.len{text-align:right}";
+
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c)
+        }
+    }
+    out
+}
+
+pub fn play_url(port: u16, code: TrackCode) -> String {
+    format!("http://127.0.0.1:{}/play/{}", port, code)
+}
+
+fn track_row(code: TrackCode, port: u16, title: &str, artist: &str, duration: f32) -> String {
+    format!("<tr><td class=\"code\">{}</td><td><a href=\"{}\">{}</a><br><small>{}</small></td><td class=\"len\">{}</td></tr>\n",
+        code, play_url(port, code), escape(title), escape(artist), if duration > 0.0 { format_duration(duration) } else { String::new() })
+}
+
+pub fn render(tracks: &[Track], sections: &TocSections, config: &BuckConfig) -> String {
+    let port = config.http.port;
+    let numbering = config.toc.numbering;
+    let mut html = String::new();
+    html.push_str("<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><meta name=\"viewport\" content=\"width=device-width\">");
+    html.push_str(&format!("<title>Buck - Library</title><style>{}</style></head><body>\n<h1>Library</h1>\n", STYLE));
+    if !sections.recently_added.is_empty() {
+        html.push_str("<h2>Recently added</h2>\n<table>\n");
+        for &i in &sections.recently_added {
+            let t = &tracks[i];
+            let (_, runtime) = album_summary(tracks, i);
+            html.push_str(&track_row(TrackCode::of(t, i, numbering), port, &t.album, &t.album_artist, runtime));
+        }
+        html.push_str("</table>\n");
+    }
+    if !sections.most_played.is_empty() {
+        html.push_str("<h2>Most played</h2>\n<table>\n");
+        for &(i, _) in &sections.most_played {
+            let t = &tracks[i];
+            html.push_str(&track_row(TrackCode::of(t, i, numbering), port, &t.title, &t.artist, t.duration));
+        }
+        html.push_str("</table>\n");
+    }
+    let mut current_album: Option<&str> = None;
+    for (i, t) in tracks.iter().enumerate() {
+        if current_album != Some(t.album.as_str()) {
+            if current_album.is_some() {
+                html.push_str("</table>\n");
+            }
+            current_album = Some(&t.album);
+            let (track_count, runtime) = album_summary(tracks, i);
+            // albums link to their first track, from where playback just carries on through the album
+            html.push_str(&format!("<h2><a href=\"{}\">{}</a></h2>\n", play_url(port, TrackCode::of(t, i, numbering)), escape(&t.album)));
+            let mut info = vec![format!("{} track{}", track_count, if track_count == 1 { "" } else { "s" }), format_duration(runtime)];
+            if !t.album_artist.is_empty() {
+                info.insert(0, escape(&t.album_artist));
+            }
+            html.push_str(&format!("<p class=\"info\">{}</p>\n<table>\n", info.join(" · ")));
+        }
+        html.push_str(&track_row(TrackCode::of(t, i, numbering), port, &t.title, &t.artist, t.duration));
+    }
+    if current_album.is_some() {
+        html.push_str("</table>\n");
+    }
+    html.push_str("</body></html>\n");
+    html
+}
+
+pub fn genhtml(tracks: &[Track], sections: &TocSections, html_output_path: &Path, config: &BuckConfig) {
+    log!("html_toc", "writing {}", html_output_path.display());
+    if let Err(e) = write_atomic(html_output_path, render(tracks, sections, config).as_bytes()) {
+        error!("html_toc", "could not write {}: {:?}", html_output_path.display(), e);
+    }
+}
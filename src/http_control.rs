@@ -0,0 +1,81 @@
+// http_control.rs
+// Control endpoint on localhost, so entries in the HTML library index can be tapped to play them
+
+use std::fs::{self, OpenOptions};
+use std::io::{Write, BufRead, BufReader};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::Duration;
+
+use crate::{ControlMsg, log, error};
+use crate::read_config::root;
+use crate::track_code::TrackCode;
+
+fn respond(stream: &mut TcpStream, status: &str, content_type: &str, body: &[u8]) {
+    let head = format!("HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n", status, content_type, body.len());
+    let _ = stream.write_all(head.as_bytes());
+    let _ = stream.write_all(body);
+    let _ = stream.flush();
+}
+
+fn handle(mut stream: TcpStream, sender: &Sender<ControlMsg>, index_path: &PathBuf) {
+    let _ = stream.set_read_timeout(Some(Duration::from_secs(5)));
+    let mut request_line = String::new();
+    if BufReader::new(&stream).read_line(&mut request_line).is_err() {
+        return;
+    }
+    // "GET /play/42.3 HTTP/1.1"
+    let mut parts = request_line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next()) {
+        (Some(m), Some(t)) => (m, t),
+        _ => {
+            respond(&mut stream, "400 Bad Request", "text/plain", b"bad request");
+            return;
+        }
+    };
+    if method != "GET" {
+        respond(&mut stream, "405 Method Not Allowed", "text/plain", b"only GET is supported");
+        return;
+    }
+    log!("http_control", "{} {}", method, target);
+    if target == "/" {
+        match fs::read(index_path) {
+            Ok(body) => respond(&mut stream, "200 OK", "text/html; charset=utf-8", &body),
+            Err(_) => respond(&mut stream, "404 Not Found", "text/plain", b"library index not generated yet")
+        }
+    } else if let Some(code) = target.strip_prefix("/play/") {
+        match code.parse::<TrackCode>() {
+            Ok(code) => {
+                let _ = sender.send(ControlMsg::PLAYCODE(code));
+                // no content keeps the browser on the index, so the next tap works straight away
+                respond(&mut stream, "204 No Content", "text/plain", b"");
+            },
+            Err(_) => respond(&mut stream, "404 Not Found", "text/plain", b"no such track")
+        }
+    } else {
+        respond(&mut stream, "404 Not Found", "text/plain", b"not found");
+    }
+}
+
+pub fn spawn(port: u16, sender: Sender<ControlMsg>, index_path: PathBuf) {
+    let listener = match TcpListener::bind(("127.0.0.1", port)) {
+        Ok(l) => l,
+        Err(e) => {
+            error!("http_control", "could not listen on port {}: {:?}", port, e);
+            return;
+        }
+    };
+    log!("http_control", "listening on 127.0.0.1:{}", port);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(s) => handle(s, &sender, &index_path),
+                Err(e) => {
+                    error!("http_control", "accept failed: {:?}", e);
+                }
+            }
+        }
+    });
+}
@@ -1,10 +1,28 @@
 mod toc;
+mod album_art;
+mod catalog;
+mod track_code;
+mod text_fonts;
+mod desktop_build;
+mod html_toc;
+mod http_control;
+mod history;
+mod bookmarks;
+mod play_order;
+mod player_state;
+mod sleep_timer;
+mod speeds;
+mod equalizer;
+mod replay_gain;
+mod failures;
 mod read_config;
 mod pointer_events;
 mod process_runner;
 mod logger;
 mod btctl_keepalive;
 mod utils;
+mod player;
+mod player_control;
 
 use process_runner::quick_run;
 use walkdir::{WalkDir};
@@ -12,11 +30,9 @@ use id3::{Tag, TagLike, frame::PictureType};
 use pointer_events::{PointerEventsReader, PointerEventsKeeper, CapturedPointerEvent, Coords};
 use std::collections::HashMap;
 use std::io::{Write, BufReader, Read};
-use std::ops::{Add, Sub};
 use std::os::unix::net::UnixListener;
 use std::path::PathBuf;
-use std::process::{Stdio, exit, ChildStdin, ChildStdout, Child};
-use std::str::FromStr;
+use std::process::{Stdio, exit};
 use std::sync::{Arc, RwLock};
 use std::thread::{self, current, sleep};
 use std::sync::mpsc::{self, Sender, Receiver};
@@ -27,8 +43,20 @@ use std::fs::{self, OpenOptions};
 use std::io::BufRead;
 use utils::elapsed::Elapsed;
 
-use crate::read_config::root;
+use crate::read_config::{root, BuckSeekConfig};
 use crate::process_runner::quick_write;
+use crate::track_code::TrackCode;
+use crate::play_order::{ShuffleMode, RepeatMode, QueueOp};
+use crate::player_state::PlayerState;
+use crate::bookmarks::Bookmarks;
+use crate::sleep_timer::SleepSetting;
+use crate::speeds::{Speeds, SpeedScope};
+use crate::replay_gain::{ReplayGain, Leveled};
+use crate::player_control::PlayerControl;
+
+// holding a seek button starts repeating after SEEK_HOLD_DELAY, then every SEEK_HOLD_REPEAT
+const SEEK_HOLD_DELAY: Duration = Duration::from_millis(500);
+const SEEK_HOLD_REPEAT: Duration = Duration::from_millis(400);
 
 #[derive(Clone)]
 pub struct Track {
@@ -40,20 +68,46 @@ pub struct Track {
     disc: u32,
     year: i32,
     album_artist: String,
-    tag: Option<Tag>
+    duration: f32,
+    album_number: u32,
+    album_track: u32,
+    added: u64,
+    replay_gain: ReplayGain,
+    // the player gave up on it
+    unplayable: bool
 }
 
 #[derive(Clone)]
 enum ControlMsg {
 
     PAUSE(),
-    SEEK_FORWARD(),
-    SEEK_BACKWARD(),
+    // by this many seconds
+    SEEK_FORWARD(f32),
+    SEEK_BACKWARD(f32),
     NEXT(),
     PREV(),
     SETVOL(u32),
     SETPOS(f32),
     SETTRACK(u32),
+    PLAYCODE(TrackCode),
+    SETSHUFFLE(ShuffleMode),
+    CYCLESHUFFLE(),
+    GETSHUFFLE(),
+    SETREPEAT(RepeatMode),
+    CYCLEREPEAT(),
+    GETREPEAT(),
+    SETSLEEP(SleepSetting),
+    CYCLESLEEP(),
+    GETSLEEP(),
+    SETSPEED(f32, SpeedScope),
+    CYCLESPEED(),
+    GETSPEED(),
+    SETEQ(String),
+    CYCLEEQ(),
+    GETEQ(),
+    // answered with a line of text for whoever asked over the socket
+    QUEUE(QueueOp, Sender<String>),
+    FAILURES(Sender<String>),
     GETVOL(),
     GETCURRENTTRACK(),
     GETCURRENTTRACKLENGTH(),
@@ -67,173 +121,32 @@ enum ControlMsg {
     VOL(u32),
     LENGTH(f32),
     POS(f32),
-    PAUSED(bool)
+    PAUSED(bool),
+    SHUFFLE(ShuffleMode),
+    REPEAT(RepeatMode),
+    SLEEP(String),
+    SPEED(f32),
+    EQ(String)
 
 }
 
-fn get_num_from_process<T: Add<Output=T> + Sub<Output=T> + FromStr, F: Fn(String) -> String>(stdout: &mut BufReader<ChildStdout>, process_string: F, goal: T) -> Option<T> {
-    let mut v: Option<T> = None;
-    loop {
-        let mut l = String::new();
-        if let Ok(a) = stdout.read_line(&mut l) {
-            if a == 0 {
-                return None;
-            }
-        }
-        l = l.replace("\n", "");
-        l = process_string(l);
-        if let Ok(a) = l.parse::<T>() {
-            v = Some(a);
-            break;
-        }
-    }
-    v
-}
-
-fn check_output_for_or_exited<F: Fn(String) -> bool>(stdout: &mut BufReader<ChildStdout>, process_string: F) -> bool {
-    loop {
-        let mut l = String::new();
-        if let Ok(a) = stdout.read_line(&mut l) {
-            if a == 0 {
-                return false;
-            }
-        }
-        l = l.replace("\n", "");
-        if process_string(l) { return true; }
-    }
-}
-
-fn is_hidden(entry: &walkdir::DirEntry) -> bool {
-    entry.file_name()
-         .to_str()
-         .map(|s| s.starts_with("."))
-         .unwrap_or(false)
-}
+fn main() -> Result<(), Box<dyn std::error::Error>> {
 
-fn kill_and_wait(child: &mut Child) {
-    if let Ok(_) = child.kill() {
-        sleep(Duration::from_millis(10));
+    // desktop mode, prepares the catalog and T.O.C. of a mounted device
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(|a| a == "--build").unwrap_or(false) {
+        desktop_build::run(&args[2..]);
+        return Ok(());
     }
-    sleep(Duration::from_millis(100));
-}
-
-struct Album {
-    artists: HashMap<String, usize>,
-    tracks: Vec<Track>
-}
-impl Album {
-    pub fn new() -> Album {
-        Album { artists: HashMap::new(), tracks: Vec::new() }
-    }
-    pub fn push(&mut self, t: Track) {
-        *self.artists.entry(t.artist.clone()).or_insert(0) += 1;
-        self.tracks.push(t);
-    }
-    pub fn tracks(&mut self) -> &mut Vec<Track> {
-        &mut self.tracks
-    }
-    pub fn artist(&self) -> String {
-        if let Some(a) = self.artists.iter().max_by(|x, y| x.1.cmp(y.1)) {
-            a.0.clone()
-        } else {
-            String::new()
-        }
-    }
-}
-
-fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     //logger test (will exit)
     //logger::test();
 
     //reading config
     let config = read_config::read_config();
+    text_fonts::init(&config);
 
-    //check catalog
-    log!("main", "reading tracks...");
-    let mut albums: HashMap<String, Album> = HashMap::new();
-    let mut albums_order: Vec<(String, i32, String)> = Vec::new();
-
-    log!("main", "opening music directories...");
-    quick_write(1, "* Cataloging...");
-    for music_dir in &config.music_dirs {
-        for entry in WalkDir::new(music_dir).into_iter().filter_entry(|e| !is_hidden(e)) {
-            let entry = result!(entry);
-            match entry.path().extension() {
-                None => continue,
-                Some(ext) => if ext != "mp3" && ext != "wav" { continue; }
-            }
-            //default values in case tag is not available
-            let mut title = entry.path().file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or("".into());
-            let mut artist = String::new();
-            let mut album = entry.path().parent().unwrap().file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or("".into());
-            let mut track: u32 = 0;
-            let mut disc: u32 = 1;
-            let mut year: i32 = 0;
-            //read tag
-            let tag_result = Tag::read_from_path(entry.path());
-            let mut tag_to_store: Option<Tag> = None;
-            if let Ok(tag) = tag_result {
-                if let Some(id3artist) = tag.artist() {
-                    artist = String::from(id3artist);
-                }
-                if let Some(id3title) = tag.title() {
-                    title = String::from(id3title);
-                }
-                if let Some(id3album) = tag.album() {
-                    album = String::from(id3album);
-                }
-                if let Some(id3track) = tag.track() {
-                    track = id3track;
-                }
-                if let Some(id3disc) = tag.disc() {
-                    disc = id3disc;
-                }
-                if let Some(id3year) = tag.year() {
-                    year = id3year;
-                }
-                if let Some(a) = tag.get("TDOR") {
-                    year = a.content().text().unwrap().parse::<i32>().unwrap();
-                } else if let Some(a) = tag.get("TORY") {
-                    year = a.content().text().unwrap().parse::<i32>().unwrap();
-                }
-                tag_to_store = Some(tag);
-            }
-            if !albums.contains_key(&album) {
-                albums.insert(album.clone(), Album::new());
-            }
-            albums_order.push((artist.clone(), year, album.clone()));
-            albums.get_mut(&album).unwrap().push(Track { path: entry.into_path(), title, artist, album, track, disc, year, album_artist: String::new(), tag: tag_to_store });
-        }
-    }
-
-    log!("main", "sorting...");
-    // sort catalog
-    albums_order.sort_by(|a, b| {
-        if a.0.eq_ignore_ascii_case(&b.0) {
-            b.1.partial_cmp(&a.1).unwrap()
-        } else {
-            a.0.partial_cmp(&b.0).unwrap()
-        }
-    });
-
-    let mut tracks: Vec<Track> = Vec::new();
-
-    for a in albums_order {
-        let album = albums.get_mut(&a.2).unwrap();
-        album.tracks().sort_by(|a, b| {
-            if a.disc == b.disc {
-                a.track.partial_cmp(&b.track).unwrap()
-            } else {
-                a.disc.partial_cmp(&b.disc).unwrap()
-            }
-        });
-        let album_artist = album.artist();
-        if let Some(first_track) = album.tracks().get_mut(0) {
-            first_track.album_artist = album_artist;
-        }
-        tracks.append(album.tracks());
-    }
+    let tracks = catalog::scan(&config, &catalog::PathMap::identity());
 
     // for now, print catalog
     /*for t in tracks.iter() {
@@ -247,246 +160,41 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     }
 
     log!("main", "starting T.O.C. generation...");
-    // generate T.O.C. pdf
-    toc::gentoc(&tracks, PathBuf::from(&config.documents_dir).join("Buck - Table of Contents.pdf"));
+    // generate T.O.C. pdf, unless it is already up to date (e.g. it was built on a PC with `buck --build`)
+    let toc_path = PathBuf::from(&config.documents_dir).join(toc::TOC_FILE_NAME);
+    let toc_sections = toc::TocSections::new(&tracks, &config, &history::PlayHistory::load());
+    if toc::is_current(&tracks, &toc_sections, &toc_path, &config) {
+        log!("main", "T.O.C. is up to date, skipping");
+    } else {
+        toc::gentoc(&tracks, &toc_sections, toc_path, &config);
+    }
+    let html_path = PathBuf::from(&config.documents_dir).join(html_toc::HTML_FILE_NAME);
+    if config.http.enabled {
+        html_toc::genhtml(&tracks, &toc_sections, &html_path, &config);
+    }
 
     log!("main", "spawning player control thread...");
     // spawn player control thread
     let (tx, rx) = mpsc::channel::<ControlMsg>();
     let (reply_tx, reply_rx) = mpsc::channel::<ControlMsg>();
+    let player = Leveled::new(player::new_backend(&config));
+    // the outgoing track of a crossfade plays on here while it fades away
+    let fading_player = if config.crossfade_secs > 0.0 { Some(Leveled::new(player::new_backend(&config))) } else { None };
+    let eq_names: Vec<String> = equalizer::presets(&config.equalizer.presets).iter().map(|p| p.name.clone()).collect();
+    // pick up where the last run left off
+    let mut control = PlayerControl::new(tracks, &config, player, fading_player, reply_tx, PlayerState::load(), Bookmarks::load(&config), Speeds::load(), history::PlayHistory::load());
     thread::spawn(move || {
         log!("player-control", "");
-        let mut btonly_keepalive: Option<btctl_keepalive::BTKeepAlive> = None;
-        let mut last_time_pos: f32 = 0.0;
-        let mut first_play = true;
-        let mut current_volume: u32 = 60;
-        let mut spawn_mplayer_base = |i: u32, current_volume: u32| {
-            first_play = true;
-            let current_volume_str = current_volume.to_string();
-            let track_path_str = tracks[i as usize].path.to_string_lossy().to_string();
-            let mut child_args = vec![
-                "-slave", "-quiet", "-volume", &current_volume_str, "-softvol", "-softvol-max", "110", &track_path_str
-            ];
-            if cfg!(feature = "kindle") {
-                child_args.insert(2, "35");
-                child_args.insert(2, "-demuxer");
-            }
-            if cfg!(feature = "btonly") {
-                child_args.insert(0, "alsa:device=bluealsa");
-                child_args.insert(0, "-ao");
-            }
-            let mut child = result!(Command::new(root("bin/mplayer")).args(child_args).stdin(Stdio::piped()).stdout(Stdio::piped()).spawn());
-            let mut stdin;
-            let mut stdout;
-            match child.stdin.take() {
-                Some(cstdin) => {
-                    stdin = cstdin;
-                },
-                None => { return None; }
-            }
-            match child.stdout.take() {
-                Some(cstdout) => {
-                    stdout = BufReader::new(cstdout);
-                },
-                None => { return None; }
-            }
-            if !check_output_for_or_exited(&mut stdout, |s: String| s.contains("AO: [alsa]")) {
-                child.wait();
-                return None;
-            }
-            Some((child, stdin, stdout))
-        };
-        let mut spawn_mplayer = |i: u32, current_volume: u32, btonly_keepalive: &mut Option<btctl_keepalive::BTKeepAlive>| {
-            let mut child;
-            let mut stdin;
-            let mut stdout;
-            quick_write(8, "* Spawning mplayer");
-            let mut attmpt_binary: bool = false;
-            loop {
-                let mplayer_result = spawn_mplayer_base(i, current_volume);
-                if let Some(mplayer) = mplayer_result {
-                    child = mplayer.0;
-                    stdin = mplayer.1;
-                    stdout = mplayer.2;
-                    break;
-                }
-                if attmpt_binary == false { attmpt_binary = true; }
-                else { attmpt_binary = false; }
-                quick_write(8, &format!("{} Mplayer spawn failed! Retrying after 5 seconds", if attmpt_binary { "|" } else { "=" }));
-                quick_write(9, "   (are you connected to a Bluetooth speaker?)");
-                sleep(Duration::from_secs(5));
-            }
-            // handle Bluetooth keep-alive for btonly devices
-            if cfg!(feature = "btonly") {
-                *btonly_keepalive = Some(btctl_keepalive::BTKeepAlive::spawn());
-            }
-            // notify UI
-            reply_tx.send(ControlMsg::NEWTRACK(i));
-            stdin.write_all(b"get_time_length\n");
-            let length_of_song = get_num_from_process(&mut stdout, |s| s.replace("ANS_LENGTH=", ""), 0.0f32).unwrap();
-            reply_tx.send(ControlMsg::LENGTH(length_of_song));
-            (child, stdin, stdout, length_of_song)
-        };
-        let mut currently_playing: u32 = 0;
-        let mut currently_paused: bool = false;
-        let mut set_currently_paused = |currently_paused: &mut bool, v: bool| {
-            *currently_paused = v;
-            reply_tx.send(ControlMsg::PAUSED(v));
-        };
-        let (mut child, mut stdin, mut stdout, mut length_of_song) = spawn_mplayer(currently_playing, current_volume, &mut btonly_keepalive);
-        set_currently_paused(&mut currently_paused, true);
-        stdin.write_all(b"pause\n"); //start paused by default
-
-        log!("player-control", "entering event loop...");
-        loop {
-            // check for control messages
-            if let Ok(m) = rx.recv_timeout(Duration::from_millis(100)) {
-                match m {
-                    ControlMsg::PAUSE() => {
-                        let new_pause_state = !currently_paused;
-                        set_currently_paused(&mut currently_paused, new_pause_state);
-                        log!("player-control", "mplayer: {}", "pause");
-                        stdin.write_all(b"pause\n");
-                    },
-                    ControlMsg::SEEK_FORWARD() => {
-                        if cfg!(feature = "btonly") {
-                            if let Some(ka) = &mut btonly_keepalive { ka.scan_on_temp(); }
-                        }
-                        set_currently_paused(&mut currently_paused, false);
-                        log!("player-control", "mplayer: {}", "seek");
-                        stdin.write_all(b"seek 5 0\n");
-                    },
-                    ControlMsg::SEEK_BACKWARD() => {
-                        if cfg!(feature = "btonly") {
-                            if let Some(ka) = &mut btonly_keepalive { ka.scan_on_temp(); }
-                        }
-                        set_currently_paused(&mut currently_paused, false);
-                        log!("player-control", "mplayer: {}", "seek");
-                        stdin.write_all(b"seek -5 0\n");
-                    },
-                    ControlMsg::SETPOS(v) => {
-                        if cfg!(feature = "btonly") {
-                            if let Some(ka) = &mut btonly_keepalive { ka.scan_on_temp(); }
-                        }
-                        set_currently_paused(&mut currently_paused, false);
-                        log!("player-control", "mplayer: {} {}", "seek", v);
-                        stdin.write_all(format!("seek {:.2} 2\n", length_of_song as f32 * v).as_bytes());
-                    },
-                    ControlMsg::NEXT() => {
-                        kill_and_wait(&mut child);
-                        currently_playing += 1;
-                        if currently_playing as usize >= tracks.len() { currently_playing = 0; }
-                        log!("player-control", "-next- removing old player, currently playing is now {}", currently_playing);
-                        let tmp = spawn_mplayer(currently_playing, current_volume, &mut btonly_keepalive);
-                        child = tmp.0;
-                        stdin = tmp.1;
-                        stdout = tmp.2;
-                        length_of_song = tmp.3;
-                        set_currently_paused(&mut currently_paused, false);
-                    },
-                    ControlMsg::PREV() => {
-                        kill_and_wait(&mut child);
-                        if currently_playing == 0 { currently_playing = (tracks.len() - 1) as u32; }
-                        else { currently_playing -= 1; }
-                        log!("player-control", "-prev- removing old player, currently playing is now {}", currently_playing);
-                        let tmp = spawn_mplayer(currently_playing, current_volume, &mut btonly_keepalive);
-                        child = tmp.0;
-                        stdin = tmp.1;
-                        stdout = tmp.2;
-                        length_of_song = tmp.3;
-                        set_currently_paused(&mut currently_paused, false);
-                    },
-                    ControlMsg::SETVOL(v) => {
-                        log!("player-control", "mplayer: volume {}", v);
-                        current_volume = v;
-                        set_currently_paused(&mut currently_paused, false);
-                        stdin.write_all(format!("volume {} 1\n", v.to_string()).as_bytes());
-                        reply_tx.send(ControlMsg::VOL(current_volume));
-                    },
-                    ControlMsg::SETTRACK(t) => {
-                        if t < tracks.len() as u32 && t >= 0 {
-                            log!("player-control", "-set- removing old player, currently playing is now {}", currently_playing);
-                            kill_and_wait(&mut child);
-                            currently_playing = t;
-                            let tmp = spawn_mplayer(currently_playing, current_volume, &mut btonly_keepalive);
-                            child = tmp.0;
-                            stdin = tmp.1;
-                            stdout = tmp.2;
-                            length_of_song = tmp.3;
-                            set_currently_paused(&mut currently_paused, false);
-                        } else {
-                            log!("player-control", "-set- track number received is out of range ({})! ignoring..", t);
-                            println!("out of range");
-                        }
-                    },
-                    ControlMsg::GETVOL() => {
-                        log!("player-control", "getvol");
-                        reply_tx.send(ControlMsg::VOL(current_volume));
-                    },
-                    ControlMsg::GETCURRENTTRACK() => {
-                        log!("player-control", "getcurrenttrack");
-                        reply_tx.send(ControlMsg::CURRENTTRACK(currently_playing));
-                    },
-                    ControlMsg::GETCURRENTTRACKLENGTH() => {
-                        log!("player-control", "getcurrenttracklength");
-                        reply_tx.send(ControlMsg::LENGTH(length_of_song));
-                    },
-                    ControlMsg::GETTRACKINFO(t) => {
-                        log!("player-control", "gettrackinfo");
-                        reply_tx.send(ControlMsg::TRACKINFO(tracks[t as usize].clone()));
-                    },
-                    ControlMsg::UIOPENED() => {
-                        log!("player-control", "ui opened");
-                        // handle Bluetooth keep-alive for btonly devices
-                        if cfg!(feature = "btonly") {
-                            btonly_keepalive = Some(btctl_keepalive::BTKeepAlive::spawn());
-                            if currently_paused {
-                                log!("player-control", "-uiopen,restart- removing old player, currently playing is now {}", currently_playing);
-                                kill_and_wait(&mut child);
-                                let tmp = spawn_mplayer(currently_playing, current_volume, &mut btonly_keepalive);
-                                child = tmp.0;
-                                stdin = tmp.1;
-                                stdout = tmp.2;
-                                length_of_song = tmp.3;
-                                stdin.write_all(format!("seek {} 2\n", last_time_pos).as_bytes());
-                                stdin.write_all(b"pause\n"); //resume paused state
-                            }
-                        }
-                    },
-                    ControlMsg::UIHIDDEN() => {
-                        log!("player-control", "ui hidden");
-                        // handle Bluetooth keep-alive for btonly devices
-                        if cfg!(feature = "btonly") && currently_paused {
-                            btonly_keepalive = None;
-                        }
-                    },
-                    _ => {}
-                }
-            }
-            // check if song has finished playing
-            if let Some(exit_status) = result!(child.try_wait()) {
-                currently_playing += 1;
-                if currently_playing >= tracks.len() as u32 { currently_playing = 0; }
-                log!("player-control", "yes! moving to next track {}", currently_playing);
-                let tmp = spawn_mplayer(currently_playing, current_volume, &mut btonly_keepalive);
-                child = tmp.0;
-                stdin = tmp.1;
-                stdout = tmp.2;
-                length_of_song = tmp.3;
-            }
-            // check song current play position
-            if !currently_paused {
-                let result = stdin.write_all(b"get_time_pos\n");
-                let mut time_pos_result = get_num_from_process(&mut stdout, |s| s.replace("ANS_TIME_POSITION=", ""), 0.0f32);
-                if let Some(time_pos) = time_pos_result {
-                    last_time_pos = time_pos;
-                    reply_tx.send(ControlMsg::POS(time_pos));
-                }
-            }
-            // repeat
-        }
+        control.start();
+        control.run(rx);
     });
 
+    // tap-to-play from the HTML library index
+    if config.http.enabled {
+        log!("main", "starting the http control endpoint...");
+        http_control::spawn(config.http.port, tx.clone(), html_path);
+    }
+
     // event manager has 'static lifetime, must exist until the end of the program
     log!("main", "booting up the events manager...");
     let mut event_manager = PointerEventsKeeper::new(PathBuf::from(config.event_paths.pointer), config.ui.width, config.ui.height);
@@ -494,7 +202,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     event_manager.start_thread();
     println!("BBB2");
     log!("main", "giving control to ui...");
-    ui(&tx, &reply_rx, event_manager, config.ui.width, config.ui.height, config.ui.scale, config.disable_scrub);
+    ui(&tx, &reply_rx, event_manager, config.ui.width, config.ui.height, config.ui.scale, config.disable_scrub, config.seek.clone(), eq_names);
 
     Ok(())
 
@@ -505,8 +213,25 @@ fn draw_album_art(path: &str) {
 }
 
 fn draw_text(text: &str, size: u32, top: u32, left: u32, style: &str, bg_color: &str, fg_color: &str) {
-    let options = format!("size={},top={},left={},style={},regular={},bold={},italic={},bolditalic={}", size, top, left, style, root("assets/Bookerly-Regular.ttf").display().to_string(), root("assets/Bookerly-Bold.ttf").display().to_string(), root("assets/Bookerly-Italic.ttf").display().to_string(), root("assets/Bookerly-BoldItalic.ttf").display().to_string());
-    quick_run("fbink", vec!["-t", &options, "-B", bg_color, "-C", fg_color, "--bgless", text]);
+    let fonts = text_fonts::get();
+    let bookerly = format!("style={},regular={},bold={},italic={},bolditalic={}", style, root(text_fonts::PRIMARY_FONT).display().to_string(), root("assets/Bookerly-Bold.ttf").display().to_string(), root("assets/Bookerly-Italic.ttf").display().to_string(), root("assets/Bookerly-BoldItalic.ttf").display().to_string());
+    if !fonts.needs_fallback(text) {
+        let options = format!("size={},top={},left={},{}", size, top, left, bookerly);
+        quick_run("fbink", vec!["-t", &options, "-B", bg_color, "-C", fg_color, "--bgless", text]);
+        return;
+    }
+    // fbink takes a single font per call, so draw run by run and advance by each run's measured width
+    let px = fonts.pt_to_px(size);
+    let mut x = left as f32;
+    for (font, run) in fonts.runs(text) {
+        let options = if font == 0 {
+            format!("px={},top={},left={},{}", px.round(), top, x.round(), bookerly)
+        } else {
+            format!("px={},top={},left={},regular={}", px.round(), top, x.round(), fonts.path(font).display().to_string())
+        };
+        quick_run("fbink", vec!["-t", &options, "-B", bg_color, "-C", fg_color, "--bgless", run]);
+        x += fonts.run_width_px(font, run, px);
+    }
 }
 
 fn draw_text_with_bg(text: &str, size: u32, top: u32, left: u32, font: &str, bg_color: &str, fg_color: &str) {
@@ -576,23 +301,23 @@ fn draw_song(track: &Track, skip_album_art: bool, width: u32, height: u32, scale
     clear_canvas_partly("GRAY6", width, 0, width, 10);
     if !skip_album_art {
         let mut has_album_cover = false;
-        if let Some(tag) = &track.tag {
-            for p in tag.pictures() {
-                if p.picture_type == PictureType::CoverFront || p.picture_type == PictureType::Other {
-                    has_album_cover = true;
-                    let mut file = OpenOptions::new()
-                        .write(true)
-                        .create(true)
-                        .open("/tmp/bucktempalbumstore").unwrap();
-                    let result = file.write_all(&p.data);
-                    if let Err(e) = result {
-                        println!("{}", &e);
-                    }
-                }
+        let tag = Tag::read_from_path(&track.path).ok();
+        if let Some(data) = tag.as_ref().and_then(album_art::embedded_cover) {
+            has_album_cover = true;
+            let mut file = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open("/tmp/bucktempalbumstore").unwrap();
+            let result = file.write_all(data);
+            if let Err(e) = result {
+                println!("{}", &e);
             }
         }
         if has_album_cover {
             draw_album_art("/tmp/bucktempalbumstore");
+        } else if let Some(cover_path) = track.path.parent().and_then(album_art::folder_cover) {
+            draw_album_art(&cover_path.display().to_string());
         } else {
             draw_album_art(&root("assets/no-album-cover.jpg").display().to_string());
         }
@@ -639,7 +364,7 @@ fn scale_calc(v: u32, scale: f32) -> u32 {
     (v as f32 * scale).round() as u32
 }
 
-fn ui(sender: &Sender<ControlMsg>, receiver: &Receiver<ControlMsg>, mut events_keeper: PointerEventsKeeper, width: u32, height: u32, scale: f32, disable_scrub: bool) {
+fn ui(sender: &Sender<ControlMsg>, receiver: &Receiver<ControlMsg>, mut events_keeper: PointerEventsKeeper, width: u32, height: u32, scale: f32, disable_scrub: bool, seek: BuckSeekConfig, eq_names: Vec<String>) {
     log!("ui", "visible is false");
     let mut player_visible: bool = false;
     let mut selector_visible: bool = false;
@@ -658,6 +383,11 @@ fn ui(sender: &Sender<ControlMsg>, receiver: &Receiver<ControlMsg>, mut events_k
     sender.send(ControlMsg::GETCURRENTTRACK());
     sender.send(ControlMsg::GETCURRENTTRACKLENGTH());
     sender.send(ControlMsg::GETVOL());
+    sender.send(ControlMsg::GETSHUFFLE());
+    sender.send(ControlMsg::GETREPEAT());
+    sender.send(ControlMsg::GETSLEEP());
+    sender.send(ControlMsg::GETSPEED());
+    sender.send(ControlMsg::GETEQ());
     let mut current_track_length: f32 = -1.0;
     let mut last_progress_chunk_leftpad: f32 = 0.0;
     let mut current_pos: f32 = 0.0;
@@ -671,14 +401,14 @@ fn ui(sender: &Sender<ControlMsg>, receiver: &Receiver<ControlMsg>, mut events_k
     let mut prev = BoundingBoxTextInteractive::new(PREV_NEXT_BTN_LR_PAD, PREV_NEXT_BTN_LR_PAD + scale_calc(100, scale), PAD_FROM_COVER_ABS, PAD_FROM_COVER_ABS + scale_calc(40, scale), 0, 0, String::from("Previous"), scale_calc(12, scale), String::from("BLACK"), String::from("WHITE"), Elapsed::new());
     
     let back5sleft = (width/2)-9-scale_calc(52, scale)-FORWARD_BACKWARD_BTN_PAD;
-    let mut back5s = BoundingBoxTextInteractive::new(back5sleft, back5sleft + scale_calc(40, scale), PAD_FROM_COVER_ABS, PAD_FROM_COVER_ABS + scale_calc(40, scale), 0, 0, String::from("< 5s"), scale_calc(12, scale), String::from("BLACK"), String::from("WHITE"), Elapsed::new());
+    let mut back5s = BoundingBoxTextInteractive::new(back5sleft, back5sleft + scale_calc(40, scale), PAD_FROM_COVER_ABS, PAD_FROM_COVER_ABS + scale_calc(40, scale), 0, 0, format!("< {}s", seek.backward_secs), scale_calc(12, scale), String::from("BLACK"), String::from("WHITE"), Elapsed::new());
 
     let playleft = (width/2)-scale_calc(9, scale);
     let mut play = BoundingBoxTextInteractive::new(playleft, playleft + scale_calc(40, scale), PAD_FROM_COVER_ABS-scale_calc(6, scale), PAD_FROM_COVER_ABS + scale_calc(40, scale), 0, 0, String::from("▶"), scale_calc(16, scale), String::from("BLACK"), String::from("WHITE"), Elapsed::new());
     let mut pause = BoundingBoxTextInteractive::new(playleft, playleft + scale_calc(40, scale), PAD_FROM_COVER_ABS, PAD_FROM_COVER_ABS + scale_calc(40, scale), 0, 0, String::from("| |"), scale_calc(12, scale), String::from("BLACK"), String::from("WHITE"), Elapsed::new());
     
     let forward5sleft = (width/2)-9+20+FORWARD_BACKWARD_BTN_PAD;
-    let mut forward5s = BoundingBoxTextInteractive::new(forward5sleft, forward5sleft + scale_calc(40, scale), PAD_FROM_COVER_ABS, PAD_FROM_COVER_ABS + scale_calc(40, scale), 0, 0, String::from("5s >"), scale_calc(12, scale), String::from("BLACK"), String::from("WHITE"), Elapsed::new());
+    let mut forward5s = BoundingBoxTextInteractive::new(forward5sleft, forward5sleft + scale_calc(40, scale), PAD_FROM_COVER_ABS, PAD_FROM_COVER_ABS + scale_calc(40, scale), 0, 0, format!("{}s >", seek.forward_secs), scale_calc(12, scale), String::from("BLACK"), String::from("WHITE"), Elapsed::new());
     
     if disable_scrub {
         back5s.disable();
@@ -692,6 +422,39 @@ fn ui(sender: &Sender<ControlMsg>, receiver: &Receiver<ControlMsg>, mut events_k
     let closetop = height-10-14-scale_calc(65, scale);
     let mut close = BoundingBoxTextInteractive::new(closeleft, width, closetop, height, scale_calc(15, scale), scale_calc(15, scale), String::from("✕"), scale_calc(20, scale), String::from("BLACK"), String::from("WHITE"), Elapsed::new());
 
+    // play mode toggles, bottom left across from the close button
+    let modesleft = scale_calc(10, scale);
+    let mut shuffle = BoundingBoxTextInteractive::new(modesleft, modesleft + scale_calc(130, scale), closetop, height, 0, scale_calc(20, scale), String::from("Shuffle: off"), scale_calc(10, scale), String::from("BLACK"), String::from("WHITE"), Elapsed::new());
+
+    let repeatleft = modesleft + scale_calc(140, scale);
+    let mut repeat = BoundingBoxTextInteractive::new(repeatleft, repeatleft + scale_calc(120, scale), closetop, height, 0, scale_calc(20, scale), String::from("Repeat: all"), scale_calc(10, scale), String::from("BLACK"), String::from("WHITE"), Elapsed::new());
+
+    let sleepleft = repeatleft + scale_calc(130, scale);
+    let mut sleep_button = BoundingBoxTextInteractive::new(sleepleft, sleepleft + scale_calc(150, scale), closetop, height, 0, scale_calc(20, scale), String::from("Sleep: off"), scale_calc(10, scale), String::from("BLACK"), String::from("WHITE"), Elapsed::new());
+
+    // one row up, the speed of the current collection
+    let speedtop = closetop - scale_calc(40, scale);
+    let mut speed_button = BoundingBoxTextInteractive::new(modesleft, modesleft + scale_calc(130, scale), speedtop, closetop, 0, scale_calc(20, scale), String::from("Speed: 1x"), scale_calc(10, scale), String::from("BLACK"), String::from("WHITE"), Elapsed::new());
+    let mut eq_button = BoundingBoxTextInteractive::new(repeatleft, repeatleft + scale_calc(150, scale), speedtop, closetop, 0, scale_calc(20, scale), String::from("EQ: Flat"), scale_calc(10, scale), String::from("BLACK"), String::from("WHITE"), Elapsed::new());
+
+    // settings screen, one row per equalizer preset
+    let mut settings_visible: bool = false;
+    let mut current_eq = String::from("Flat");
+    let eq_row_height = scale_calc(50, scale);
+    let mut eq_choices: Vec<BoundingBoxTextInteractive> = eq_names.iter().enumerate().map(|(i, name)| {
+        let top = scale_calc(80, scale) + eq_row_height * i as u32;
+        BoundingBoxTextInteractive::new(0, width, top, top + eq_row_height, scale_calc(20, scale), scale_calc(10, scale), name.clone(), scale_calc(12, scale), String::from("BLACK"), String::from("WHITE"), Elapsed::new())
+    }).collect();
+    let mut settings_back = BoundingBoxTextInteractive::new(closeleft, width, closetop, height, scale_calc(15, scale), scale_calc(15, scale), String::from("✕"), scale_calc(20, scale), String::from("BLACK"), String::from("WHITE"), Elapsed::new());
+    let draw_settings = |eq_choices: &mut Vec<BoundingBoxTextInteractive>, current_eq: &str| {
+        clear_canvas("BLACK");
+        draw_text("Equalizer", scale_calc(17, scale), scale_calc(20, scale), scale_calc(20, scale), "regular", "BLACK", "WHITE");
+        for (choice, name) in eq_choices.iter_mut().zip(eq_names.iter()) {
+            choice.content = if name == current_eq { format!("● {}", name) } else { format!("○ {}", name) };
+            choice.draw();
+        }
+    };
+
     let mut volume_control = BoundingBoxTextInteractive::new(0, width, 0, height/2, 0, 0, String::new(), 1, String::from("BLACK"), String::from("WHITE"), Elapsed::new());
     let mut seek_control = BoundingBoxTextInteractive::new(0, width, height/2, width, 0, 0, String::new(), 1, String::from("BLACK"), String::from("WHITE"), Elapsed::new());
 
@@ -712,7 +475,8 @@ fn ui(sender: &Sender<ControlMsg>, receiver: &Receiver<ControlMsg>, mut events_k
         numerals.push(BoundingBoxTextInteractive::new(letter_width*i, letter_width*(i+1), numerals_y_start, box_y_end, selector_pad, 0, value.to_string(), 20, String::from("WHITE"), String::from("BLACK"), Elapsed::new()));
     }
     numerals.push(BoundingBoxTextInteractive::new(letter_width*11, letter_width*12, numerals_y_start, box_y_end, selector_pad, 0, String::from("←"), 20, String::from("WHITE"), String::from("BLACK"), Elapsed::new()));
-    numerals.push(BoundingBoxTextInteractive::new(letter_width*12, width, numerals_y_start, box_y_end, selector_pad, 0, String::from("OK"), 20, String::from("WHITE"), String::from("BLACK"), Elapsed::new()));
+    numerals.push(BoundingBoxTextInteractive::new(letter_width*12, letter_width*13, numerals_y_start, box_y_end, selector_pad, 0, String::from("."), 20, String::from("WHITE"), String::from("BLACK"), Elapsed::new()));
+    numerals.push(BoundingBoxTextInteractive::new(letter_width*13, width, numerals_y_start, box_y_end, selector_pad, 0, String::from("OK"), 20, String::from("WHITE"), String::from("BLACK"), Elapsed::new()));
 
     let mut set_numeral_display = |v: &str, numerals: &Vec<BoundingBoxTextInteractive>, first_run: bool| {
         clear_canvas_partly("WHITE", box_y_start, lr_pad, width-lr_pad, numerals_y_start-5-box_y_start);
@@ -731,6 +495,8 @@ fn ui(sender: &Sender<ControlMsg>, receiver: &Receiver<ControlMsg>, mut events_k
     let mut current_album: String = String::new();
     let mut currently_paused: bool = true;
     let mut current_selection_panel_value: String = String::new();
+    // (forward, last step, when to seek again) while a seek button is held down
+    let mut seek_hold: Option<(bool, f32, Instant)> = None;
 
     'eventloop: loop {
         // process new pointer events
@@ -751,6 +517,11 @@ fn ui(sender: &Sender<ControlMsg>, receiver: &Receiver<ControlMsg>, mut events_k
                                 } else if i == 10 {
                                     current_selection_panel_value = rem_last(&current_selection_panel_value).to_string();
                                     set_numeral_display(&current_selection_panel_value, &numerals, false);
+                                } else if i == 11 {
+                                    if !current_selection_panel_value.contains('.') {
+                                        current_selection_panel_value.push('.');
+                                        set_numeral_display(&current_selection_panel_value, &numerals, false);
+                                    }
                                 } else {
                                     selector_visible = false;
                                     println!("ABC611");
@@ -760,24 +531,66 @@ fn ui(sender: &Sender<ControlMsg>, receiver: &Receiver<ControlMsg>, mut events_k
                                     println!("ABC614");
                                     events_keeper.start_thread();
                                     println!("ABC615");
-                                    if let Ok(new_track) = current_selection_panel_value.parse::<u32>() {
-                                        sender.send(ControlMsg::SETTRACK(new_track-1));
+                                    if let Ok(code) = current_selection_panel_value.parse::<TrackCode>() {
+                                        sender.send(ControlMsg::PLAYCODE(code));
                                     }
                                 }
                                 break;
                             }
                         }
+                    } else if settings_visible {
+                        let mut done = settings_back.colliding_coords(&coords);
+                        for (choice, name) in eq_choices.iter_mut().zip(eq_names.iter()) {
+                            if choice.colliding_coords(&coords) {
+                                sender.send(ControlMsg::SETEQ(name.clone()));
+                                done = true;
+                                break;
+                            }
+                        }
+                        if done {
+                            settings_visible = false;
+                            player_visible = true;
+                            if let Some(current_track) = &current_track {
+                                draw_all(current_track, vec![&mut prev,
+                                                &mut back5s,
+                                                &mut pause,
+                                                &mut forward5s,
+                                                &mut next,
+                                                &mut shuffle,
+                                                &mut repeat,
+                                                &mut sleep_button,
+                                                &mut speed_button,
+                                                &mut eq_button,
+                                                &mut close], &mut true, width, height, scale);
+                                draw_two_state(&currently_paused, &play, &pause);
+                            }
+                        }
                     } else if player_visible {
                         if play.colliding_coords(&coords) || pause.colliding_coords(&coords) {
                             sender.send(ControlMsg::PAUSE());
                         } else if back5s.colliding_coords(&coords) {
-                            sender.send(ControlMsg::SEEK_BACKWARD());
+                            sender.send(ControlMsg::SEEK_BACKWARD(seek.backward_secs));
+                            seek_hold = Some((false, seek.backward_secs, Instant::now() + SEEK_HOLD_DELAY));
                         } else if forward5s.colliding_coords(&coords) {
-                            sender.send(ControlMsg::SEEK_FORWARD());
+                            sender.send(ControlMsg::SEEK_FORWARD(seek.forward_secs));
+                            seek_hold = Some((true, seek.forward_secs, Instant::now() + SEEK_HOLD_DELAY));
                         } else if prev.colliding_coords(&coords) {
                             sender.send(ControlMsg::PREV());
                         } else if next.colliding_coords(&coords) {
                             sender.send(ControlMsg::NEXT());
+                        } else if shuffle.colliding_coords(&coords) {
+                            sender.send(ControlMsg::CYCLESHUFFLE());
+                        } else if repeat.colliding_coords(&coords) {
+                            sender.send(ControlMsg::CYCLEREPEAT());
+                        } else if sleep_button.colliding_coords(&coords) {
+                            sender.send(ControlMsg::CYCLESLEEP());
+                        } else if speed_button.colliding_coords(&coords) {
+                            sender.send(ControlMsg::CYCLESPEED());
+                        } else if eq_button.colliding_coords(&coords) {
+                            // the player screen stays grabbed underneath, it just isn't drawn to
+                            player_visible = false;
+                            settings_visible = true;
+                            draw_settings(&mut eq_choices, &current_eq);
                         } else if close.colliding_coords(&coords) {
                             sender.send(ControlMsg::UIHIDDEN());
                             player_visible = false;
@@ -808,10 +621,19 @@ fn ui(sender: &Sender<ControlMsg>, receiver: &Receiver<ControlMsg>, mut events_k
                     }
                 },
                 CapturedPointerEvent::PointerOff(coords) => {
+                    seek_hold = None;
                 }
             }
             e = events_keeper.rx.recv_timeout(Duration::from_millis(50));
         }
+        // a seek button still held down seeks again, further each time
+        if let Some((forward, step, next_at)) = seek_hold {
+            if Instant::now() >= next_at {
+                let step = (step * seek.hold_growth).min(seek.hold_max_secs);
+                sender.send(if forward { ControlMsg::SEEK_FORWARD(step) } else { ControlMsg::SEEK_BACKWARD(step) });
+                seek_hold = Some((forward, step, Instant::now() + SEEK_HOLD_REPEAT));
+            }
+        }
         let mut e = receiver.recv_timeout(Duration::from_millis(50));
 
         // filter out duplicate typed control messages
@@ -876,6 +698,11 @@ fn ui(sender: &Sender<ControlMsg>, receiver: &Receiver<ControlMsg>, mut events_k
                                                 &mut pause,
                                                 &mut forward5s,
                                                 &mut next,
+                                                &mut shuffle,
+                                                &mut repeat,
+                                                &mut sleep_button,
+                                                &mut speed_button,
+                                                &mut eq_button,
                                                 &mut close], &mut current_album_is_new, width, height, scale);
                     }
                 },
@@ -916,6 +743,42 @@ fn ui(sender: &Sender<ControlMsg>, receiver: &Receiver<ControlMsg>, mut events_k
                         draw_two_state(&b, &play, &pause);
                     }
                 },
+                ControlMsg::SHUFFLE(mode) => {
+                    shuffle.content = format!("Shuffle: {}", mode.label());
+                    if player_visible {
+                        shuffle.draw_over();
+                        shuffle.draw();
+                    }
+                },
+                ControlMsg::EQ(name) => {
+                    eq_button.content = format!("EQ: {}", name);
+                    current_eq = name;
+                    if player_visible {
+                        eq_button.draw_over();
+                        eq_button.draw();
+                    }
+                },
+                ControlMsg::SPEED(speed) => {
+                    speed_button.content = format!("Speed: {}", speeds::label(speed));
+                    if player_visible {
+                        speed_button.draw_over();
+                        speed_button.draw();
+                    }
+                },
+                ControlMsg::SLEEP(label) => {
+                    sleep_button.content = format!("Sleep: {}", label);
+                    if player_visible {
+                        sleep_button.draw_over();
+                        sleep_button.draw();
+                    }
+                },
+                ControlMsg::REPEAT(mode) => {
+                    repeat.content = format!("Repeat: {}", mode.label());
+                    if player_visible {
+                        repeat.draw_over();
+                        repeat.draw();
+                    }
+                },
                 ControlMsg::VOL(new_vol) => {
                     if player_visible {
                         draw_text_with_bg(&format!(" Volume {: >3} ", new_vol.to_string()), 9, width-50, 2, &root("assets/LinLibertine_M.otf").display().to_string(), "BLACK", "WHITE");
@@ -941,12 +804,87 @@ fn ui(sender: &Sender<ControlMsg>, receiver: &Receiver<ControlMsg>, mut events_k
                         events_keeper.start_thread();
                         println!("ABC315");
                         clear_canvas_partly("WHITE", box_y_start, lr_pad, width-lr_pad, height_8_segments_height);
+                        current_selection_panel_value.clear();
                         set_numeral_display("", &numerals, true);
                     }
+                } else if cmd.starts_with("play ") {
+                    match cmd["play ".len()..].parse::<TrackCode>() {
+                        Ok(code) => { sender.send(ControlMsg::PLAYCODE(code)); },
+                        Err(_) => {
+                            log!("ui", "invalid track code {:?}", cmd);
+                        }
+                    }
+                } else if cmd.starts_with("shuffle") {
+                    // `shuffle` steps to the next mode, `shuffle albums` picks one
+                    match cmd["shuffle".len()..].trim() {
+                        "" => { sender.send(ControlMsg::CYCLESHUFFLE()); },
+                        arg => match ShuffleMode::parse(arg) {
+                            Some(mode) => { sender.send(ControlMsg::SETSHUFFLE(mode)); },
+                            None => {
+                                log!("ui", "unknown shuffle mode {:?}", arg);
+                            }
+                        }
+                    }
+                } else if cmd.starts_with("repeat") {
+                    match cmd["repeat".len()..].trim() {
+                        "" => { sender.send(ControlMsg::CYCLEREPEAT()); },
+                        arg => match RepeatMode::parse(arg) {
+                            Some(mode) => { sender.send(ControlMsg::SETREPEAT(mode)); },
+                            None => {
+                                log!("ui", "unknown repeat mode {:?}", arg);
+                            }
+                        }
+                    }
+                } else if cmd.starts_with("eq") {
+                    // `eq` steps through the presets, `eq spoken word` picks one by name
+                    match cmd["eq".len()..].trim() {
+                        "" => { sender.send(ControlMsg::CYCLEEQ()); },
+                        name => { sender.send(ControlMsg::SETEQ(name.to_string())); }
+                    }
+                } else if cmd.starts_with("speed") {
+                    // `speed` steps the collection through the presets, `speed 1.5` or `speed track 1.5` sets it
+                    match cmd["speed".len()..].trim() {
+                        "" => { sender.send(ControlMsg::CYCLESPEED()); },
+                        arg => match speeds::parse(arg) {
+                            Some((speed, scope)) => { sender.send(ControlMsg::SETSPEED(speed, scope)); },
+                            None => {
+                                log!("ui", "invalid speed {:?}, {} to {}", arg, speeds::MIN_SPEED, speeds::MAX_SPEED);
+                            }
+                        }
+                    }
+                } else if cmd.starts_with("sleep") {
+                    // `sleep` steps through the presets, `sleep 30`, `sleep track`, `sleep album` or `sleep off`
+                    match cmd["sleep".len()..].trim() {
+                        "" => { sender.send(ControlMsg::CYCLESLEEP()); },
+                        arg => match SleepSetting::parse(arg) {
+                            Some(setting) => { sender.send(ControlMsg::SETSLEEP(setting)); },
+                            None => {
+                                log!("ui", "unknown sleep setting {:?}", arg);
+                            }
+                        }
+                    }
+                } else if cmd.starts_with("failures") {
+                    // the tracks skipped as unplayable, for cleaning up
+                    let (answer_tx, answer_rx) = mpsc::channel::<String>();
+                    sender.send(ControlMsg::FAILURES(answer_tx));
+                    let answer = answer_rx.recv_timeout(Duration::from_secs(2)).unwrap_or(String::from("no answer from the player"));
+                    socket.write_all(format!("{}\n", answer).as_bytes());
+                } else if cmd.starts_with("queue") {
+                    // answered like failures, buck-cli prints it
+                    let answer = match QueueOp::parse(&cmd["queue".len()..]) {
+                        Some(op) => {
+                            let (answer_tx, answer_rx) = mpsc::channel::<String>();
+                            sender.send(ControlMsg::QUEUE(op, answer_tx));
+                            answer_rx.recv_timeout(Duration::from_secs(2)).unwrap_or(String::from("no answer from the player"))
+                        },
+                        None => String::from("usage: queue [list | add [album] <code> | next [album] <code> | remove <n> | clear]")
+                    };
+                    socket.write_all(format!("{}\n", answer).as_bytes());
                 } else if cmd.starts_with("ui") {
                     if let Some(current_track) = &current_track {
                         sender.send(ControlMsg::UIOPENED());
                         player_visible = true;
+                        settings_visible = false;
                         println!("ABC111");
                         events_keeper.end_thread();
                         println!("ABC112");
@@ -959,6 +897,11 @@ fn ui(sender: &Sender<ControlMsg>, receiver: &Receiver<ControlMsg>, mut events_k
                                                 &mut pause,
                                                 &mut forward5s,
                                                 &mut next,
+                                                &mut shuffle,
+                                                &mut repeat,
+                                                &mut sleep_button,
+                                                &mut speed_button,
+                                                &mut eq_button,
                                                 &mut close], &mut true, width, height, scale);
                         draw_two_state(&currently_paused, &play, &pause);
                         clear_canvas_partly("GRAYD", width, 0, last_progress_chunk_leftpad as u32, 10);
@@ -0,0 +1,546 @@
+// play_order.rs
+// Order tracks are played in, straight through the catalog or shuffled
+
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Serialize, Deserialize};
+
+use crate::{Track, log};
+use crate::read_config::root;
+use crate::track_code::TrackCode;
+
+const MAX_HISTORY: usize = 500;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum ShuffleMode {
+    Off,
+    Tracks,
+    // albums in random order, each one played through in track order
+    Albums,
+    // only the tracks of the artist playing when shuffle was turned on
+    Artist
+}
+
+impl Default for ShuffleMode {
+    fn default() -> ShuffleMode {
+        ShuffleMode::Off
+    }
+}
+
+impl ShuffleMode {
+    pub fn cycle(self) -> ShuffleMode {
+        match self {
+            ShuffleMode::Off => ShuffleMode::Tracks,
+            ShuffleMode::Tracks => ShuffleMode::Albums,
+            ShuffleMode::Albums => ShuffleMode::Artist,
+            ShuffleMode::Artist => ShuffleMode::Off
+        }
+    }
+    pub fn label(self) -> &'static str {
+        match self {
+            ShuffleMode::Off => "off",
+            ShuffleMode::Tracks => "tracks",
+            ShuffleMode::Albums => "albums",
+            ShuffleMode::Artist => "artist"
+        }
+    }
+    pub fn parse(s: &str) -> Option<ShuffleMode> {
+        match s.trim() {
+            "off" => Some(ShuffleMode::Off),
+            "tracks" | "on" => Some(ShuffleMode::Tracks),
+            "albums" => Some(ShuffleMode::Albums),
+            "artist" => Some(ShuffleMode::Artist),
+            _ => None
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum RepeatMode {
+    // stop, paused on the first track, after the last one
+    Off,
+    One,
+    Album,
+    All
+}
+
+impl Default for RepeatMode {
+    fn default() -> RepeatMode {
+        RepeatMode::All
+    }
+}
+
+impl RepeatMode {
+    pub fn cycle(self) -> RepeatMode {
+        match self {
+            RepeatMode::Off => RepeatMode::All,
+            RepeatMode::All => RepeatMode::Album,
+            RepeatMode::Album => RepeatMode::One,
+            RepeatMode::One => RepeatMode::Off
+        }
+    }
+    pub fn label(self) -> &'static str {
+        match self {
+            RepeatMode::Off => "off",
+            RepeatMode::One => "one",
+            RepeatMode::Album => "album",
+            RepeatMode::All => "all"
+        }
+    }
+    pub fn parse(s: &str) -> Option<RepeatMode> {
+        match s.trim() {
+            "off" => Some(RepeatMode::Off),
+            "one" | "track" => Some(RepeatMode::One),
+            "album" => Some(RepeatMode::Album),
+            "all" | "on" => Some(RepeatMode::All),
+            _ => None
+        }
+    }
+}
+
+// kept in the player state, the seed makes a shuffled order come back the same
+#[derive(Serialize, Deserialize, Default, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct PlayModes {
+    pub shuffle: ShuffleMode,
+    pub seed: u64,
+    pub repeat: RepeatMode
+}
+
+pub fn new_seed() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos() as u64).unwrap_or(0x2545F4914F6CDD1D)
+}
+
+// splitmix64, plenty for shuffling and the same on every device
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^ (z >> 31)
+    }
+    fn shuffle<T>(&mut self, v: &mut [T]) {
+        for i in (1..v.len()).rev() {
+            let j = (self.next() % (i as u64 + 1)) as usize;
+            v.swap(i, j);
+        }
+    }
+}
+
+fn artist_of(t: &Track) -> &str {
+    if t.album_artist.is_empty() { &t.artist } else { &t.album_artist }
+}
+
+pub struct PlayOrder {
+    mode: ShuffleMode,
+    repeat: RepeatMode,
+    seed: u64,
+    order: Vec<u32>,
+    pos: usize,
+    // tracks actually played, for Previous
+    history: Vec<u32>,
+    // picked to play before the order carries on
+    queue: VecDeque<u32>,
+    // set while a track from the queue plays, the order stays where it was meanwhile
+    playing_queued: Option<u32>
+}
+
+impl PlayOrder {
+    pub fn new(tracks: &[Track], modes: &PlayModes, current: u32) -> PlayOrder {
+        let mut p = PlayOrder { mode: modes.shuffle, repeat: modes.repeat, seed: modes.seed, order: Vec::new(), pos: 0, history: Vec::new(), queue: VecDeque::new(), playing_queued: None };
+        p.rebuild(tracks, current);
+        p
+    }
+    fn rebuild(&mut self, tracks: &[Track], current: u32) {
+        let mut rng = Rng(self.seed);
+        self.order = match self.mode {
+            ShuffleMode::Off => (0..tracks.len() as u32).collect(),
+            ShuffleMode::Tracks => {
+                let mut order: Vec<u32> = (0..tracks.len() as u32).collect();
+                rng.shuffle(&mut order);
+                order
+            },
+            ShuffleMode::Albums => {
+                let mut albums: Vec<Vec<u32>> = Vec::new();
+                let mut album_index: HashMap<u32, usize> = HashMap::new();
+                for (i, t) in tracks.iter().enumerate() {
+                    let a = *album_index.entry(t.album_number).or_insert_with(|| {
+                        albums.push(Vec::new());
+                        albums.len() - 1
+                    });
+                    albums[a].push(i as u32);
+                }
+                rng.shuffle(&mut albums);
+                albums.concat()
+            },
+            ShuffleMode::Artist => {
+                let artist = tracks.get(current as usize).map(artist_of).unwrap_or("");
+                let mut order: Vec<u32> = (0..tracks.len() as u32).filter(|&i| artist_of(&tracks[i as usize]) == artist).collect();
+                rng.shuffle(&mut order);
+                order
+            }
+        };
+        self.pos = self.order.iter().position(|&t| t == current).unwrap_or(0);
+        self.playing_queued = None;
+        log!("play_order", "{} order of {} tracks, at {}", self.mode.label(), self.order.len(), self.pos);
+    }
+    pub fn mode(&self) -> ShuffleMode {
+        self.mode
+    }
+    pub fn modes(&self) -> PlayModes {
+        PlayModes { shuffle: self.mode, seed: self.seed, repeat: self.repeat }
+    }
+    pub fn set_mode(&mut self, tracks: &[Track], mode: ShuffleMode, current: u32) {
+        self.mode = mode;
+        self.seed = new_seed();
+        self.rebuild(tracks, current);
+    }
+    pub fn repeat(&self) -> RepeatMode {
+        self.repeat
+    }
+    pub fn set_repeat(&mut self, repeat: RepeatMode) {
+        self.repeat = repeat;
+    }
+    // None only with nothing in the catalog
+    pub fn first(&self) -> Option<u32> {
+        self.order.first().copied()
+    }
+    // what plays when the current track runs out, None once the end is reached with repeat off
+    pub fn auto_next(&self, tracks: &[Track]) -> Option<u32> {
+        if let Some(&t) = self.queue.front() {
+            return Some(t);
+        }
+        let current = self.current();
+        match self.repeat {
+            RepeatMode::One => Some(current),
+            // albums are contiguous in the catalog, so wrap back to where this one starts
+            RepeatMode::Album => {
+                let album = tracks[current as usize].album_number;
+                let following = current as usize + 1;
+                if following < tracks.len() && tracks[following].album_number == album {
+                    Some(following as u32)
+                } else {
+                    let mut start = current as usize;
+                    while start > 0 && tracks[start - 1].album_number == album {
+                        start -= 1;
+                    }
+                    Some(start as u32)
+                }
+            },
+            RepeatMode::All => self.peek_next(),
+            // while the queue plays, "the end" is still the end of the order
+            RepeatMode::Off => if self.pos + 1 < self.order.len() { self.peek_next() } else { None }
+        }
+    }
+    // an empty order stands on track 0, like a first start does
+    pub fn current(&self) -> u32 {
+        self.playing_queued.or_else(|| self.order.get(self.pos).copied()).unwrap_or(0)
+    }
+    pub fn peek_next(&self) -> Option<u32> {
+        if self.order.is_empty() {
+            return None;
+        }
+        Some(self.order[(self.pos + 1) % self.order.len()])
+    }
+    // the order from just after the current track, once round; the queue is left alone
+    pub fn upcoming(&self) -> Vec<u32> {
+        let n = self.order.len();
+        (1..=n).map(|i| self.order[(self.pos + i) % n]).collect()
+    }
+    fn remember(&mut self) {
+        let current = self.current();
+        self.history.push(current);
+        if self.history.len() > MAX_HISTORY {
+            self.history.remove(0);
+        }
+    }
+    fn move_to(&mut self, tracks: &[Track], t: u32) {
+        self.playing_queued = None;
+        match self.order.iter().position(|&o| o == t) {
+            Some(p) => self.pos = p,
+            None => self.rebuild(tracks, t)
+        }
+    }
+    // Next pressed
+    pub fn next(&mut self) -> u32 {
+        self.remember();
+        if let Some(t) = self.queue.pop_front() {
+            self.playing_queued = Some(t);
+            return t;
+        }
+        self.playing_queued = None;
+        if !self.order.is_empty() {
+            self.pos = (self.pos + 1) % self.order.len();
+        }
+        self.current()
+    }
+    // the current track ran out, None at the end of the order with repeat off
+    pub fn advance(&mut self, tracks: &[Track]) -> Option<u32> {
+        if let Some(t) = self.queue.pop_front() {
+            self.remember();
+            self.playing_queued = Some(t);
+            return Some(t);
+        }
+        let t = self.auto_next(tracks)?;
+        if t != self.current() {
+            self.remember();
+            self.move_to(tracks, t);
+        }
+        Some(t)
+    }
+    pub fn prev(&mut self, tracks: &[Track]) -> u32 {
+        self.playing_queued = None;
+        match self.history.pop() {
+            Some(t) => {
+                // the track may be by another artist than the one being shuffled now
+                match self.order.iter().position(|&o| o == t) {
+                    Some(p) => self.pos = p,
+                    None => self.rebuild(tracks, t)
+                }
+                t
+            },
+            None => {
+                self.pos = if self.pos == 0 { self.order.len().saturating_sub(1) } else { self.pos - 1 };
+                self.current()
+            }
+        }
+    }
+    // moving on past a file that won't play, it isn't kept for Previous
+    pub fn skip_to(&mut self, tracks: &[Track], t: u32) {
+        self.move_to(tracks, t);
+    }
+    // a track picked by hand, the order carries on from there
+    pub fn jump_to(&mut self, tracks: &[Track], t: u32) {
+        // repeat-one coming round again isn't worth going back to
+        if t != self.current() {
+            self.remember();
+        }
+        self.move_to(tracks, t);
+    }
+    pub fn enqueue(&mut self, t: &[u32]) {
+        self.queue.extend(t);
+    }
+    // ahead of everything already queued, keeping t in order
+    pub fn play_next(&mut self, t: &[u32]) {
+        for (i, &x) in t.iter().enumerate() {
+            self.queue.insert(i, x);
+        }
+    }
+    pub fn remove_queued(&mut self, i: usize) -> Option<u32> {
+        self.queue.remove(i)
+    }
+    pub fn clear_queue(&mut self) {
+        self.queue.clear();
+    }
+    pub fn queued(&self) -> &VecDeque<u32> {
+        &self.queue
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum QueueTarget {
+    Track(TrackCode),
+    // the whole album the track is on
+    Album(TrackCode)
+}
+
+impl QueueTarget {
+    fn parse(args: &[&str]) -> Option<QueueTarget> {
+        match args {
+            ["album", code] => code.parse().ok().map(QueueTarget::Album),
+            [code] => code.parse().ok().map(QueueTarget::Track),
+            _ => None
+        }
+    }
+    pub fn resolve(&self, tracks: &[Track]) -> Option<Vec<u32>> {
+        match self {
+            QueueTarget::Track(code) => code.resolve(tracks).map(|t| vec![t]),
+            QueueTarget::Album(code) => {
+                let album = tracks[code.resolve(tracks)? as usize].album_number;
+                Some((0..tracks.len() as u32).filter(|&i| tracks[i as usize].album_number == album).collect())
+            }
+        }
+    }
+}
+
+// the socket's `queue ...` commands, `queue` on its own lists
+#[derive(Clone, Copy, Debug)]
+pub enum QueueOp {
+    Add(QueueTarget),
+    PlayNext(QueueTarget),
+    // 1-based, as listed
+    Remove(usize),
+    Clear,
+    List
+}
+
+impl QueueOp {
+    pub fn parse(s: &str) -> Option<QueueOp> {
+        let words: Vec<&str> = s.split_whitespace().collect();
+        match words.as_slice() {
+            [] | ["list"] => Some(QueueOp::List),
+            ["clear"] => Some(QueueOp::Clear),
+            ["remove", n] => n.parse().ok().filter(|&n| n >= 1).map(QueueOp::Remove),
+            ["add", rest @ ..] => QueueTarget::parse(rest).map(QueueOp::Add),
+            ["next", rest @ ..] => QueueTarget::parse(rest).map(QueueOp::PlayNext),
+            _ => None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::{PlayOrder, PlayModes, ShuffleMode, RepeatMode};
+    use crate::Track;
+    use crate::replay_gain::ReplayGain;
+
+    // two albums by one artist, then one by another, in catalog order
+    fn catalog() -> Vec<Track> {
+        [("X", 1, 1), ("X", 1, 2), ("X", 1, 3), ("X", 2, 1), ("X", 2, 2), ("Y", 3, 1), ("Y", 3, 2)].iter().map(|&(artist, album_number, track)| Track {
+            path: PathBuf::from(format!("/music/{}/{}/{}.mp3", artist, album_number, track)),
+            title: format!("{}.{}", album_number, track),
+            artist: String::from(artist),
+            album: format!("Album {}", album_number),
+            track,
+            disc: 1,
+            year: 0,
+            album_artist: String::new(),
+            duration: 60.0,
+            album_number,
+            album_track: track,
+            added: 0,
+            replay_gain: ReplayGain::default(),
+            unplayable: false
+        }).collect()
+    }
+
+    fn order(tracks: &[Track], shuffle: ShuffleMode, repeat: RepeatMode, current: u32) -> PlayOrder {
+        PlayOrder::new(tracks, &PlayModes { shuffle, seed: 42, repeat }, current)
+    }
+
+    #[test]
+    fn track_shuffle_plays_everything_once_and_comes_back_with_its_seed() {
+        let tracks = catalog();
+        let p = order(&tracks, ShuffleMode::Tracks, RepeatMode::All, 3);
+        assert_eq!(p.current(), 3);
+        let mut sorted = p.order.clone();
+        sorted.sort();
+        assert_eq!(sorted, (0..7).collect::<Vec<u32>>());
+        assert_eq!(order(&tracks, ShuffleMode::Tracks, RepeatMode::All, 3).order, p.order);
+    }
+
+    #[test]
+    fn album_shuffle_keeps_each_album_together_and_in_order() {
+        let tracks = catalog();
+        let p = order(&tracks, ShuffleMode::Albums, RepeatMode::All, 0);
+        let mut albums: Vec<Vec<u32>> = Vec::new();
+        for &t in &p.order {
+            match albums.last_mut() {
+                Some(album) if tracks[album[0] as usize].album_number == tracks[t as usize].album_number => album.push(t),
+                _ => albums.push(vec![t])
+            }
+        }
+        albums.sort();
+        assert_eq!(albums, vec![vec![0, 1, 2], vec![3, 4], vec![5, 6]]);
+    }
+
+    #[test]
+    fn artist_shuffle_sticks_to_the_artist_playing() {
+        let tracks = catalog();
+        let mut p = order(&tracks, ShuffleMode::Artist, RepeatMode::All, 5);
+        let mut sorted = p.order.clone();
+        sorted.sort();
+        assert_eq!(sorted, vec![5, 6]);
+        // picking a track by someone else starts over on their tracks
+        p.jump_to(&tracks, 1);
+        assert_eq!(p.order.len(), 5);
+        assert_eq!(p.current(), 1);
+    }
+
+    #[test]
+    fn repeat_off_stops_after_the_last_track() {
+        let tracks = catalog();
+        let mut p = order(&tracks, ShuffleMode::Off, RepeatMode::Off, 5);
+        assert_eq!(p.advance(&tracks), Some(6));
+        assert_eq!(p.auto_next(&tracks), None);
+        assert_eq!(p.advance(&tracks), None);
+        assert_eq!(p.first(), Some(0));
+    }
+
+    #[test]
+    fn repeat_all_goes_round_again() {
+        let tracks = catalog();
+        let mut p = order(&tracks, ShuffleMode::Off, RepeatMode::All, 6);
+        assert_eq!(p.advance(&tracks), Some(0));
+    }
+
+    #[test]
+    fn repeat_album_goes_back_to_the_start_of_the_album() {
+        let tracks = catalog();
+        let mut p = order(&tracks, ShuffleMode::Off, RepeatMode::Album, 3);
+        assert_eq!(p.advance(&tracks), Some(4));
+        assert_eq!(p.advance(&tracks), Some(3));
+    }
+
+    #[test]
+    fn repeat_one_stays_put_until_next_is_pressed() {
+        let tracks = catalog();
+        let mut p = order(&tracks, ShuffleMode::Off, RepeatMode::One, 2);
+        assert_eq!(p.advance(&tracks), Some(2));
+        assert_eq!(p.next(), 3);
+    }
+
+    #[test]
+    fn queued_tracks_play_first_then_the_order_carries_on() {
+        let tracks = catalog();
+        let mut p = order(&tracks, ShuffleMode::Off, RepeatMode::All, 0);
+        p.enqueue(&[5]);
+        p.play_next(&[3]);
+        assert_eq!(p.auto_next(&tracks), Some(3));
+        assert_eq!(p.advance(&tracks), Some(3));
+        assert_eq!(p.next(), 5);
+        assert!(p.queued().is_empty());
+        assert_eq!(p.advance(&tracks), Some(1));
+        // Previous goes back through what actually played
+        assert_eq!(p.prev(&tracks), 5);
+        assert_eq!(p.prev(&tracks), 3);
+        assert_eq!(p.prev(&tracks), 0);
+    }
+
+    #[test]
+    fn skipping_past_a_file_leaves_the_queue_and_history_alone() {
+        let tracks = catalog();
+        let mut p = order(&tracks, ShuffleMode::Off, RepeatMode::All, 4);
+        p.enqueue(&[1]);
+        assert_eq!(p.next(), 1);
+        // the queued track won't play, the order carries on after where it stood
+        assert_eq!(p.upcoming(), vec![5, 6, 0, 1, 2, 3, 4]);
+        p.enqueue(&[2]);
+        p.skip_to(&tracks, 5);
+        assert_eq!(p.current(), 5);
+        assert_eq!(p.queued().len(), 1);
+        assert_eq!(p.prev(&tracks), 4);
+    }
+
+    #[test]
+    fn an_empty_catalog_has_nothing_to_play() {
+        let mut p = order(&[], ShuffleMode::Off, RepeatMode::All, 0);
+        assert_eq!(p.first(), None);
+        assert_eq!(p.peek_next(), None);
+        assert!(p.upcoming().is_empty());
+        assert_eq!(p.auto_next(&[]), None);
+        assert_eq!(p.advance(&[]), None);
+        assert_eq!(p.next(), 0);
+        assert_eq!(p.prev(&[]), 0);
+    }
+}
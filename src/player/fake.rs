@@ -0,0 +1,116 @@
+// player/fake.rs
+// Scripted backend that plays nothing, its clock advances one tick per position() call
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use super::{PlayerBackend, PlayerEvent, LoadError};
+
+pub const DEFAULT_LENGTH: f32 = 180.0;
+// the control thread asks for the position every 100ms
+const TICK: f32 = 0.1;
+
+pub struct FakeBackend {
+    default_length: f32,
+    lengths: HashMap<PathBuf, f32>,
+    unplayable: HashSet<PathBuf>,
+    loaded: Option<PathBuf>,
+    preloaded: Option<PathBuf>,
+    position: f32,
+    length: f32,
+    paused: bool,
+    volume: u32
+}
+
+impl FakeBackend {
+    pub fn new(default_length: f32) -> FakeBackend {
+        FakeBackend { default_length, lengths: HashMap::new(), unplayable: HashSet::new(), loaded: None, preloaded: None, position: 0.0, length: 0.0, paused: false, volume: 0 }
+    }
+    // the builders script a library for the player control tests
+    #[cfg(test)]
+    pub fn with_length(mut self, path: &Path, secs: f32) -> FakeBackend {
+        self.lengths.insert(path.to_path_buf(), secs);
+        self
+    }
+    // load() fails for these, like mplayer does for a broken file
+    #[cfg(test)]
+    pub fn with_unplayable(mut self, path: &Path) -> FakeBackend {
+        self.unplayable.insert(path.to_path_buf());
+        self
+    }
+    fn clamp(&mut self) {
+        self.position = self.position.max(0.0).min(self.length);
+    }
+}
+
+impl PlayerBackend for FakeBackend {
+    fn load(&mut self, path: &Path, volume: u32) -> Result<(), LoadError> {
+        self.loaded = None;
+        self.preloaded = None;
+        if self.unplayable.contains(path) {
+            return Err(LoadError::File);
+        }
+        self.loaded = Some(path.to_path_buf());
+        self.length = self.lengths.get(path).copied().unwrap_or(self.default_length);
+        self.position = 0.0;
+        self.paused = false;
+        self.volume = volume;
+        Ok(())
+    }
+    fn preload(&mut self, path: &Path) -> bool {
+        if self.loaded.is_none() || self.unplayable.contains(path) {
+            return false;
+        }
+        self.preloaded = Some(path.to_path_buf());
+        true
+    }
+    fn clear_preload(&mut self) {
+        self.preloaded = None;
+    }
+    fn toggle_pause(&mut self) {
+        self.paused = !self.paused;
+    }
+    fn seek_relative(&mut self, secs: f32) {
+        self.position += secs;
+        self.paused = false;
+        self.clamp();
+    }
+    fn seek_absolute(&mut self, secs: f32) {
+        self.position = secs;
+        self.paused = false;
+        self.clamp();
+    }
+    fn set_volume(&mut self, volume: u32) {
+        self.volume = volume;
+        self.paused = false;
+    }
+    fn position(&mut self) -> Option<f32> {
+        self.loaded.as_ref()?;
+        if !self.paused {
+            self.position += TICK;
+            self.clamp();
+        }
+        Some(self.position)
+    }
+    fn length(&self) -> f32 {
+        self.length
+    }
+    fn poll_event(&mut self) -> Option<PlayerEvent> {
+        self.loaded.as_ref()?;
+        if self.position >= self.length {
+            if let Some(next) = self.preloaded.take() {
+                self.length = self.lengths.get(&next).copied().unwrap_or(self.default_length);
+                self.loaded = Some(next);
+                self.position = 0.0;
+                return Some(PlayerEvent::Advanced);
+            }
+            self.loaded = None;
+            return Some(PlayerEvent::EndOfTrack);
+        }
+        None
+    }
+    fn stop(&mut self) {
+        self.preloaded = None;
+        self.loaded = None;
+    }
+}
@@ -0,0 +1,83 @@
+// player/mod.rs
+// Playback backends, the control thread only talks to these through PlayerBackend
+
+pub mod mplayer;
+pub mod mpv;
+pub mod native;
+pub mod sink;
+pub mod fake;
+
+use std::path::Path;
+
+use serde::{Serialize, Deserialize};
+
+use crate::equalizer::Bands;
+use crate::read_config::BuckConfig;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum PlayerKind {
+    Mplayer,
+    Mpv,
+    // decodes in-process, see native.rs
+    Native,
+    // plays nothing, for trying Buck out on a desktop without audio hardware
+    Fake
+}
+
+impl Default for PlayerKind {
+    fn default() -> PlayerKind {
+        PlayerKind::Mplayer
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum PlayerEvent {
+    EndOfTrack,
+    // the preloaded track took over without a gap, length() and position() now describe it
+    Advanced,
+    // the audio output went away mid-track (e.g. the Bluetooth speaker dropped), the track itself is fine
+    OutputFailed
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum LoadError {
+    // no audio device (or no Bluetooth speaker), worth waiting for
+    Output,
+    // this file won't play, the next one may
+    File
+}
+
+// positions and lengths are in seconds, volume in percent
+pub trait PlayerBackend {
+    // starts playing path from the beginning
+    fn load(&mut self, path: &Path, volume: u32) -> Result<(), LoadError>;
+    // queues path to follow the current track seamlessly, false if the backend can't (or the file is unplayable),
+    // in which case EndOfTrack comes and the next track is loaded the usual way
+    fn preload(&mut self, _path: &Path) -> bool { false }
+    // nothing is to follow the current track after all
+    fn clear_preload(&mut self) {}
+    fn toggle_pause(&mut self);
+    fn seek_relative(&mut self, secs: f32);
+    fn seek_absolute(&mut self, secs: f32);
+    fn set_volume(&mut self, volume: u32);
+    // 1.0 is normal, pitch stays the same; unlike seeks and volume this leaves pause alone.
+    // Returns the speed now playing, backends that can't change speed stay at 1.0
+    fn set_speed(&mut self, _speed: f32) -> f32 { 1.0 }
+    // takes effect on the playing track without starting it over, and stays for the tracks after it
+    fn set_equalizer(&mut self, _bands: &Bands) {}
+    fn position(&mut self) -> Option<f32>;
+    fn length(&self) -> f32;
+    // polled by the control thread every loop, None while the track is still playing
+    fn poll_event(&mut self) -> Option<PlayerEvent>;
+    fn stop(&mut self);
+}
+
+pub fn new_backend(config: &BuckConfig) -> Box<dyn PlayerBackend + Send> {
+    match config.player {
+        PlayerKind::Mplayer => Box::new(mplayer::MplayerBackend::new()),
+        PlayerKind::Mpv => Box::new(mpv::MpvBackend::new()),
+        PlayerKind::Native => Box::new(native::NativeBackend::new(sink::new_sink(&config.native))),
+        PlayerKind::Fake => Box::new(fake::FakeBackend::new(fake::DEFAULT_LENGTH))
+    }
+}
@@ -0,0 +1,322 @@
+// player/mplayer.rs
+// mplayer in slave mode, one process per track
+
+use std::fs::{self, OpenOptions};
+use std::fmt;
+use std::io::{Read, Write, BufRead, BufReader};
+use std::path::Path;
+use std::process::{Command, Stdio, Child, ChildStdin, ChildStdout, ChildStderr};
+use std::sync::mpsc::{self, Sender, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::{log, error};
+use crate::equalizer::{Bands, FLAT};
+use crate::read_config::root;
+use super::{PlayerBackend, PlayerEvent, LoadError};
+
+// how long mplayer gets to open the audio output, and to answer a query
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+const ANSWER_TIMEOUT: Duration = Duration::from_secs(2);
+// unanswered queries in a row before a stalled mplayer is given up on
+const MAX_TIMEOUTS: u32 = 3;
+
+// what the reader thread makes of mplayer's output, anything else it prints is dropped
+#[derive(Debug)]
+enum Reply {
+    // ANS_LENGTH=215.3 -> ("LENGTH", "215.3")
+    Answer(String, String),
+    // the audio output is open, playback is under way
+    AudioReady,
+    // mplayer could not open the audio device
+    OutputFailed,
+    // the file is missing, or not something mplayer can play
+    FileFailed,
+    // stdout and stderr closed, mplayer is gone
+    Closed
+}
+
+#[derive(Debug)]
+enum MplayerError {
+    Exited,
+    Timeout,
+    Write(std::io::Error),
+    Unparsable(String)
+}
+
+impl fmt::Display for MplayerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MplayerError::Exited => write!(f, "mplayer exited"),
+            MplayerError::Timeout => write!(f, "no answer in time"),
+            MplayerError::Write(e) => write!(f, "could not write to mplayer: {}", e),
+            MplayerError::Unparsable(v) => write!(f, "unexpected answer {:?}", v)
+        }
+    }
+}
+
+fn parse_line(line: &str) -> Option<Reply> {
+    if let Some(answer) = line.strip_prefix("ANS_") {
+        let (name, value) = answer.split_once('=')?;
+        return Some(Reply::Answer(name.to_string(), value.trim().trim_matches('\'').to_string()));
+    }
+    if line.starts_with("AO: [") {
+        return Some(Reply::AudioReady);
+    }
+    if line.contains("Could not open/initialize audio device") {
+        return Some(Reply::OutputFailed);
+    }
+    if ["Failed to recognize file format", "Cannot open file", "File not found"].iter().any(|m| line.contains(m)) {
+        return Some(Reply::FileFailed);
+    }
+    None
+}
+
+// false once nobody is listening any more
+fn forward_lines<R: Read>(from: R, tx: &Sender<Reply>) -> bool {
+    let mut from = BufReader::new(from);
+    let mut line = String::new();
+    loop {
+        line.clear();
+        match from.read_line(&mut line) {
+            Ok(0) | Err(_) => return true,
+            Ok(_) => {
+                if let Some(reply) = parse_line(line.trim_end()) {
+                    if tx.send(reply).is_err() {
+                        return false;
+                    }
+                }
+            }
+        }
+    }
+}
+
+// runs until mplayer closes both outputs, so nothing else ever blocks reading them.
+// Most of the complaints about files and audio devices go to stderr
+fn spawn_reader(stdout: ChildStdout, stderr: ChildStderr) -> Receiver<Reply> {
+    let (tx, rx) = mpsc::channel();
+    let errors_tx = tx.clone();
+    let errors = thread::spawn(move || { forward_lines(stderr, &errors_tx); });
+    thread::spawn(move || {
+        // Closed comes after everything stderr had to say
+        if forward_lines(stdout, &tx) && errors.join().is_ok() {
+            let _ = tx.send(Reply::Closed);
+        }
+    });
+    rx
+}
+
+// waits for a reply the filter picks out, until the timeout or mplayer exiting
+fn wait_for<T, F: FnMut(Reply) -> Option<T>>(replies: &Receiver<Reply>, timeout: Duration, mut filter: F) -> Result<T, MplayerError> {
+    let started = Instant::now();
+    loop {
+        let left = timeout.checked_sub(started.elapsed()).ok_or(MplayerError::Timeout)?;
+        match replies.recv_timeout(left) {
+            Ok(Reply::Closed) | Err(RecvTimeoutError::Disconnected) => return Err(MplayerError::Exited),
+            Ok(reply) => if let Some(v) = filter(reply) { return Ok(v); },
+            Err(RecvTimeoutError::Timeout) => return Err(MplayerError::Timeout)
+        }
+    }
+}
+
+fn kill_and_wait(child: &mut Child) {
+    let _ = child.kill();
+    // reaps it, a killed process exits straight away
+    let _ = child.wait();
+}
+
+struct MplayerProcess {
+    child: Child,
+    stdin: ChildStdin,
+    replies: Receiver<Reply>,
+    // the reader saw stdout close, or stdin would not take any more
+    closed: bool,
+    timeouts: u32
+}
+
+impl MplayerProcess {
+    fn send(&mut self, cmd: &str) -> Result<(), MplayerError> {
+        let result = self.stdin.write_all(format!("{}\n", cmd).as_bytes()).map_err(MplayerError::Write);
+        if result.is_err() {
+            self.closed = true;
+        }
+        result
+    }
+    // sends a get_* command and waits for its ANS_<name> line, answers left over from earlier queries are skipped
+    fn query(&mut self, cmd: &str, name: &str) -> Result<f32, MplayerError> {
+        if self.closed {
+            return Err(MplayerError::Exited);
+        }
+        while let Ok(reply) = self.replies.try_recv() {
+            if let Reply::Closed = reply {
+                self.closed = true;
+                return Err(MplayerError::Exited);
+            }
+        }
+        self.send(cmd)?;
+        let value = wait_for(&self.replies, ANSWER_TIMEOUT, |r| match r {
+            Reply::Answer(n, v) if n == name => Some(v),
+            _ => None
+        });
+        match value {
+            Err(MplayerError::Exited) => self.closed = true,
+            Err(MplayerError::Timeout) => self.timeouts += 1,
+            _ => self.timeouts = 0
+        }
+        let value = value?;
+        value.parse().map_err(|_| MplayerError::Unparsable(value))
+    }
+}
+
+pub struct MplayerBackend {
+    process: Option<MplayerProcess>,
+    length: f32,
+    equalizer: Bands
+}
+
+// gains for mplayer's equalizer filter, g1:g2:...:g10
+fn equalizer_args(bands: &Bands) -> String {
+    bands.iter().map(|g| format!("{:.1}", g)).collect::<Vec<_>>().join(":")
+}
+
+impl MplayerBackend {
+    pub fn new() -> MplayerBackend {
+        MplayerBackend { process: None, length: 0.0, equalizer: FLAT }
+    }
+    fn spawn(path: &Path, volume: u32, equalizer: &Bands) -> Result<MplayerProcess, LoadError> {
+        let current_volume_str = volume.to_string();
+        let filters = format!("scaletempo,equalizer={}", equalizer_args(equalizer));
+        let track_path_str = path.to_string_lossy().to_string();
+        let mut child_args = vec![
+            "-slave", "-quiet", "-volume", &current_volume_str, "-softvol", "-softvol-max", "110", "-af", &filters, &track_path_str
+        ];
+        if cfg!(feature = "kindle") {
+            child_args.insert(2, "35");
+            child_args.insert(2, "-demuxer");
+        }
+        if cfg!(feature = "btonly") {
+            child_args.insert(0, "alsa:device=bluealsa");
+            child_args.insert(0, "-ao");
+        }
+        let mut child = match Command::new(root("bin/mplayer")).args(child_args).stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped()).spawn() {
+            Ok(c) => c,
+            Err(e) => {
+                error!("mplayer", "could not start mplayer: {:?}", e);
+                return Err(LoadError::Output);
+            }
+        };
+        let stdin = child.stdin.take().ok_or(LoadError::Output)?;
+        let replies = spawn_reader(child.stdout.take().ok_or(LoadError::Output)?, child.stderr.take().ok_or(LoadError::Output)?);
+        // the last complaint before mplayer gave up says whose fault it was
+        let mut cause: Option<LoadError> = None;
+        let started = wait_for(&replies, STARTUP_TIMEOUT, |r| match r {
+            Reply::AudioReady => Some(()),
+            Reply::OutputFailed => { cause = Some(LoadError::Output); None },
+            Reply::FileFailed => { cause = Some(LoadError::File); None },
+            _ => None
+        });
+        if let Err(e) = started {
+            error!("mplayer", "mplayer did not start playing {}: {} ({:?})", path.display(), e, cause);
+            kill_and_wait(&mut child);
+            // a file is only blamed when mplayer names what is wrong with it. Hanging or quitting without a word
+            // is more likely the audio device (or mplayer itself), which is retried without skipping anything
+            return Err(cause.unwrap_or(LoadError::Output));
+        }
+        Ok(MplayerProcess { child, stdin, replies, closed: false, timeouts: 0 })
+    }
+    fn command(&mut self, cmd: &str) {
+        log!("mplayer", "{}", cmd);
+        if let Some(p) = &mut self.process {
+            if let Err(e) = p.send(cmd) {
+                error!("mplayer", "could not send {:?}: {}", cmd, e);
+            }
+        }
+    }
+}
+
+impl PlayerBackend for MplayerBackend {
+    fn load(&mut self, path: &Path, volume: u32) -> Result<(), LoadError> {
+        self.stop();
+        let mut process = MplayerBackend::spawn(path, volume, &self.equalizer)?;
+        self.length = match process.query("get_time_length", "LENGTH") {
+            Ok(length) => length,
+            Err(e) => {
+                error!("mplayer", "no length for {}: {}", path.display(), e);
+                0.0
+            }
+        };
+        self.process = Some(process);
+        Ok(())
+    }
+    fn toggle_pause(&mut self) {
+        self.command("pause");
+    }
+    fn seek_relative(&mut self, secs: f32) {
+        self.command(&format!("seek {} 0", secs));
+    }
+    fn seek_absolute(&mut self, secs: f32) {
+        self.command(&format!("seek {:.2} 2", secs));
+    }
+    fn set_volume(&mut self, volume: u32) {
+        self.command(&format!("volume {} 1", volume));
+    }
+    fn set_speed(&mut self, speed: f32) -> f32 {
+        self.command(&format!("pausing_keep_force speed_set {:.2}", speed));
+        let p = match self.process.as_mut() {
+            Some(p) => p,
+            None => return 1.0
+        };
+        // mplayer clamps the speed and builds without scaletempo ignore it, so ask what it settled on
+        match p.query("pausing_keep_force get_property speed", "speed") {
+            Ok(speed) => speed,
+            Err(e) => {
+                error!("mplayer", "no speed: {}", e);
+                1.0
+            }
+        }
+    }
+    fn set_equalizer(&mut self, bands: &Bands) {
+        self.equalizer = *bands;
+        self.command(&format!("pausing_keep_force af_cmdline equalizer {}", equalizer_args(bands)));
+    }
+    fn position(&mut self) -> Option<f32> {
+        let p = self.process.as_mut()?;
+        match p.query("get_time_pos", "TIME_POSITION") {
+            Ok(pos) => Some(pos),
+            // finished tracks stop answering, poll_event picks that up
+            Err(MplayerError::Exited) => None,
+            Err(e) => {
+                error!("mplayer", "no position: {}", e);
+                None
+            }
+        }
+    }
+    fn length(&self) -> f32 {
+        self.length
+    }
+    fn poll_event(&mut self) -> Option<PlayerEvent> {
+        let p = self.process.as_mut()?;
+        if p.timeouts >= MAX_TIMEOUTS {
+            error!("mplayer", "mplayer stopped answering, moving on");
+            kill_and_wait(&mut p.child);
+            self.process = None;
+            return Some(PlayerEvent::EndOfTrack);
+        }
+        match p.child.try_wait() {
+            Ok(Some(_)) => {
+                self.process = None;
+                Some(PlayerEvent::EndOfTrack)
+            },
+            Ok(None) => None,
+            Err(e) => {
+                error!("mplayer", "could not check on mplayer: {:?}", e);
+                None
+            }
+        }
+    }
+    fn stop(&mut self) {
+        if let Some(mut p) = self.process.take() {
+            kill_and_wait(&mut p.child);
+        }
+    }
+}
@@ -0,0 +1,319 @@
+// player/mpv.rs
+// mpv over its JSON IPC socket, a single idle process plays every track
+
+use std::fs::{self, OpenOptions};
+use std::io::{Write, BufRead, BufReader};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::process::{Command, Stdio, Child};
+use std::sync::{Arc, Mutex, Condvar};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread::{self, sleep};
+use std::time::{Duration, Instant};
+
+use serde_json::{json, Value};
+
+use crate::{log, error};
+use crate::equalizer::{Bands, BAND_FREQS, FLAT, is_flat};
+use crate::read_config::root;
+use super::{PlayerBackend, PlayerEvent, LoadError};
+
+const SOCKET_PATH: &str = "/tmp/buck-mpv.sock";
+// crossfading runs a second instance, which needs a socket of its own
+static INSTANCES: AtomicUsize = AtomicUsize::new(0);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const LOAD_TIMEOUT: Duration = Duration::from_secs(5);
+
+// ids for observe_property, mpv sends them back with every change
+const OBSERVED: [(u64, &str); 4] = [(1, "time-pos"), (2, "duration"), (3, "pause"), (4, "eof-reached")];
+
+// kept up to date by the reader thread from property change events
+#[derive(Default)]
+struct MpvState {
+    time_pos: Option<f32>,
+    duration: Option<f32>,
+    paused: bool,
+    eof: bool,
+    file_loaded: bool,
+    // the appended track started playing by itself
+    advanced: bool,
+    load_failed: bool,
+    // the file was fine, the audio output wasn't
+    output_failed: bool,
+    disconnected: bool
+}
+
+type SharedState = Arc<(Mutex<MpvState>, Condvar)>;
+
+struct MpvProcess {
+    child: Child,
+    socket: UnixStream
+}
+
+pub struct MpvBackend {
+    process: Option<MpvProcess>,
+    state: SharedState,
+    loaded: bool,
+    socket_path: String,
+    equalizer: Bands
+}
+
+fn handle_message(line: &str, state: &SharedState) {
+    let msg: Value = match serde_json::from_str(line) {
+        Ok(v) => v,
+        Err(_) => return
+    };
+    let (lock, cvar) = &**state;
+    let mut s = lock.lock().unwrap();
+    match msg["event"].as_str() {
+        Some("property-change") => {
+            let data = &msg["data"];
+            match msg["name"].as_str() {
+                Some("time-pos") => s.time_pos = data.as_f64().map(|v| v as f32),
+                Some("duration") => s.duration = data.as_f64().map(|v| v as f32),
+                Some("pause") => s.paused = data.as_bool().unwrap_or(false),
+                // changes still queued from the previous file arrive before its file-loaded
+                Some("eof-reached") => s.eof = s.file_loaded && data.as_bool().unwrap_or(false),
+                _ => {}
+            }
+        },
+        Some("file-loaded") => {
+            if s.file_loaded {
+                s.advanced = true;
+                s.eof = false;
+                s.time_pos = None;
+                s.duration = None;
+            }
+            s.file_loaded = true;
+        },
+        Some("end-file") => {
+            if msg["reason"].as_str() == Some("error") {
+                error!("mpv", "could not play file: {}", msg["file_error"]);
+                s.load_failed = true;
+                s.output_failed = msg["file_error"].as_str().map(|e| e.contains("audio output")).unwrap_or(false);
+            }
+        },
+        _ => {}
+    }
+    cvar.notify_all();
+}
+
+impl MpvBackend {
+    pub fn new() -> MpvBackend {
+        let socket_path = match INSTANCES.fetch_add(1, Ordering::SeqCst) {
+            0 => SOCKET_PATH.to_string(),
+            n => SOCKET_PATH.replace(".sock", &format!("-{}.sock", n + 1))
+        };
+        MpvBackend { process: None, state: Arc::new((Mutex::new(MpvState::default()), Condvar::new())), loaded: false, socket_path, equalizer: FLAT }
+    }
+    fn spawn(socket_path: &str, volume: u32, state: &SharedState) -> Option<MpvProcess> {
+        let _ = fs::remove_file(socket_path);
+        let mut args = vec![
+            "--idle=yes".to_string(), "--keep-open=yes".to_string(), "--no-video".to_string(), "--no-terminal".to_string(),
+            format!("--input-ipc-server={}", socket_path), format!("--volume={}", volume), "--volume-max=110".to_string()
+        ];
+        if cfg!(feature = "btonly") {
+            args.push("--audio-device=alsa/bluealsa".to_string());
+        }
+        let mut child = match Command::new(root("bin/mpv")).args(args).stdin(Stdio::null()).stdout(Stdio::null()).spawn() {
+            Ok(c) => c,
+            Err(e) => {
+                error!("mpv", "could not start mpv: {:?}", e);
+                return None;
+            }
+        };
+        // the socket shows up once mpv has initialised
+        let started = Instant::now();
+        let socket = loop {
+            if let Ok(s) = UnixStream::connect(socket_path) {
+                break s;
+            }
+            if started.elapsed() > CONNECT_TIMEOUT || matches!(child.try_wait(), Ok(Some(_))) {
+                error!("mpv", "no IPC socket at {}", socket_path);
+                let _ = child.kill();
+                let _ = child.wait();
+                return None;
+            }
+            sleep(Duration::from_millis(50));
+        };
+        let reader = BufReader::new(socket.try_clone().ok()?);
+        let state = state.clone();
+        thread::spawn(move || {
+            for line in reader.lines() {
+                match line {
+                    Ok(l) => handle_message(&l, &state),
+                    Err(_) => break
+                }
+            }
+            let (lock, cvar) = &*state;
+            lock.lock().unwrap().disconnected = true;
+            cvar.notify_all();
+        });
+        let mut process = MpvProcess { child, socket };
+        for (id, name) in OBSERVED.iter() {
+            MpvBackend::send(&mut process, json!(["observe_property", id, name]));
+        }
+        Some(process)
+    }
+    fn send(process: &mut MpvProcess, command: Value) {
+        log!("mpv", "{}", command);
+        let line = format!("{}\n", json!({ "command": command }));
+        if let Err(e) = process.socket.write_all(line.as_bytes()) {
+            error!("mpv", "could not send command: {:?}", e);
+        }
+    }
+    fn command(&mut self, command: Value) {
+        if let Some(p) = &mut self.process {
+            MpvBackend::send(p, command);
+        }
+    }
+    // a labelled chain of lavfi peaking filters, swapped as a whole
+    fn apply_equalizer(&mut self) {
+        self.command(json!(["af", "remove", "@eq"]));
+        if is_flat(&self.equalizer) {
+            return;
+        }
+        let chain: Vec<String> = BAND_FREQS.iter().zip(self.equalizer.iter())
+            .filter(|(_, g)| **g != 0.0)
+            .map(|(f, g)| format!("equalizer=f={}:t=o:w=1:g={:.1}", f, g))
+            .collect();
+        self.command(json!(["af", "add", format!("@eq:lavfi=[{}]", chain.join(","))]));
+    }
+    fn shutdown(&mut self) {
+        if let Some(mut p) = self.process.take() {
+            let _ = p.child.kill();
+            let _ = p.child.wait();
+        }
+        self.loaded = false;
+    }
+}
+
+impl PlayerBackend for MpvBackend {
+    fn load(&mut self, path: &Path, volume: u32) -> Result<(), LoadError> {
+        if self.process.is_none() {
+            *self.state.0.lock().unwrap() = MpvState::default();
+            self.process = MpvBackend::spawn(&self.socket_path, volume, &self.state);
+            if self.process.is_none() {
+                return Err(LoadError::Output);
+            }
+            self.apply_equalizer();
+        }
+        {
+            let mut s = self.state.0.lock().unwrap();
+            *s = MpvState { paused: s.paused, ..MpvState::default() };
+        }
+        self.command(json!(["set_property", "volume", volume]));
+        self.command(json!(["set_property", "pause", false]));
+        self.command(json!(["playlist-clear"]));
+        self.command(json!(["loadfile", path.to_string_lossy(), "replace"]));
+        // wait until mpv knows the length, or gives up on the file
+        let (lock, cvar) = &*self.state;
+        let started = Instant::now();
+        let mut s = lock.lock().unwrap();
+        while !(s.file_loaded && s.duration.is_some()) && !s.load_failed && !s.disconnected {
+            let left = match LOAD_TIMEOUT.checked_sub(started.elapsed()) {
+                Some(d) => d,
+                None => break
+            };
+            s = cvar.wait_timeout(s, left).unwrap().0;
+        }
+        // a file mpv chokes on without saying so counts against the file, not the output
+        let result = if s.disconnected || s.output_failed {
+            Err(LoadError::Output)
+        } else if s.file_loaded && !s.load_failed {
+            Ok(())
+        } else {
+            Err(LoadError::File)
+        };
+        let disconnected = s.disconnected;
+        drop(s);
+        if disconnected {
+            self.shutdown();
+        }
+        self.loaded = result.is_ok();
+        result
+    }
+    // mpv plays playlist entries back to back, so appending is all gapless playback takes
+    fn preload(&mut self, path: &Path) -> bool {
+        if !self.loaded {
+            return false;
+        }
+        // drops whatever was appended before, the current track stays
+        self.command(json!(["playlist-clear"]));
+        self.command(json!(["loadfile", path.to_string_lossy(), "append"]));
+        true
+    }
+    fn clear_preload(&mut self) {
+        self.command(json!(["playlist-clear"]));
+    }
+    fn toggle_pause(&mut self) {
+        self.command(json!(["cycle", "pause"]));
+    }
+    // mplayer resumes playback on seeks and volume changes, the control thread relies on that
+    fn seek_relative(&mut self, secs: f32) {
+        self.command(json!(["seek", secs, "relative"]));
+        self.command(json!(["set_property", "pause", false]));
+    }
+    fn seek_absolute(&mut self, secs: f32) {
+        self.command(json!(["seek", secs, "absolute"]));
+        self.command(json!(["set_property", "pause", false]));
+    }
+    fn set_volume(&mut self, volume: u32) {
+        self.command(json!(["set_property", "volume", volume]));
+        self.command(json!(["set_property", "pause", false]));
+    }
+    // mpv keeps the pitch with its default scaletempo filter
+    fn set_speed(&mut self, speed: f32) -> f32 {
+        self.command(json!(["set_property", "speed", speed]));
+        speed
+    }
+    fn set_equalizer(&mut self, bands: &Bands) {
+        self.equalizer = *bands;
+        self.apply_equalizer();
+    }
+    fn position(&mut self) -> Option<f32> {
+        if !self.loaded {
+            return None;
+        }
+        self.state.0.lock().unwrap().time_pos
+    }
+    fn length(&self) -> f32 {
+        self.state.0.lock().unwrap().duration.unwrap_or(0.0)
+    }
+    fn poll_event(&mut self) -> Option<PlayerEvent> {
+        if !self.loaded {
+            return None;
+        }
+        let (eof, disconnected) = {
+            let mut s = self.state.0.lock().unwrap();
+            // wait for the new length, the control thread passes it straight on to the UI
+            if s.advanced && s.duration.is_some() {
+                s.advanced = false;
+                return Some(PlayerEvent::Advanced);
+            }
+            (s.eof || s.load_failed, s.disconnected)
+        };
+        if disconnected {
+            error!("mpv", "lost connection to mpv");
+            self.shutdown();
+            return Some(PlayerEvent::EndOfTrack);
+        }
+        if eof {
+            self.loaded = false;
+            return Some(PlayerEvent::EndOfTrack);
+        }
+        None
+    }
+    fn stop(&mut self) {
+        if self.loaded {
+            self.command(json!(["stop"]));
+            self.loaded = false;
+        }
+    }
+}
+
+impl Drop for MpvBackend {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
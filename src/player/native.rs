@@ -0,0 +1,431 @@
+// player/native.rs
+// In-process playback, symphonia decodes on a thread of our own and writes to a Sink
+
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Sender, Receiver, TryRecvError};
+use std::thread;
+
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::{Decoder, DecoderOptions};
+use symphonia::core::errors::Error as DecodeError;
+use symphonia::core::formats::{FormatOptions, FormatReader, SeekMode, SeekTo};
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+use symphonia::core::units::{Time, TimeBase};
+
+use crate::{log, error};
+use crate::catalog::probe_duration;
+use crate::equalizer::{Bands, Filter, FLAT};
+use crate::read_config::root;
+use super::{PlayerBackend, PlayerEvent, LoadError};
+use super::sink::Sink;
+
+// same headroom as mplayer's -softvol-max 110
+const MAX_GAIN: f32 = 1.1;
+
+struct Source {
+    format: Box<dyn FormatReader>,
+    decoder: Box<dyn Decoder>,
+    track_id: u32,
+    time_base: TimeBase,
+    rate: u32
+}
+
+impl Source {
+    fn open(path: &Path) -> Option<(Source, f32)> {
+        let file = match File::open(path) {
+            Ok(f) => f,
+            Err(e) => {
+                error!("native", "could not open {}: {:?}", path.display(), e);
+                return None;
+            }
+        };
+        let mss = MediaSourceStream::new(Box::new(file), Default::default());
+        let mut hint = Hint::new();
+        if let Some(ext) = path.extension().and_then(|e| e.to_str()) {
+            hint.with_extension(ext);
+        }
+        let format = match symphonia::default::get_probe().format(&hint, mss, &FormatOptions { enable_gapless: true, ..Default::default() }, &MetadataOptions::default()) {
+            Ok(p) => p.format,
+            Err(e) => {
+                error!("native", "unsupported file {}: {:?}", path.display(), e);
+                return None;
+            }
+        };
+        let track = format.default_track()?;
+        let params = track.codec_params.clone();
+        let decoder = match symphonia::default::get_codecs().make(&params, &DecoderOptions::default()) {
+            Ok(d) => d,
+            Err(e) => {
+                error!("native", "no decoder for {}: {:?}", path.display(), e);
+                return None;
+            }
+        };
+        let rate = params.sample_rate?;
+        let time_base = params.time_base.unwrap_or_else(|| TimeBase::new(1, rate));
+        let length = match params.n_frames {
+            Some(n) => {
+                let t = time_base.calc_time(n);
+                t.seconds as f32 + t.frac as f32
+            },
+            None => probe_duration(path).unwrap_or(0.0)
+        };
+        Some((Source { track_id: track.id, format, decoder, time_base, rate }, length))
+    }
+    // timestamps are in time_base units, positions are counted in sample frames
+    fn ts_to_frames(&self, ts: u64) -> u64 {
+        let t = self.time_base.calc_time(ts);
+        t.seconds * self.rate as u64 + (t.frac * self.rate as f64).round() as u64
+    }
+}
+
+enum Command {
+    // numbered, so events still in flight from the previous track can be told apart
+    Load(Source, u64),
+    // follows the current track without draining the sink in between
+    Preload(Source, u64),
+    ClearPreload,
+    TogglePause,
+    Seek(f32),
+    Volume(u32),
+    Equalizer(Bands),
+    Stop
+}
+
+#[derive(Default)]
+struct Shared {
+    // frames that have actually left the sink, so seconds = frames_heard / rate
+    frames_heard: u64,
+    rate: u32,
+    eof: bool,
+    output_failed: bool,
+    generation: u64
+}
+
+struct Playback {
+    sink: Box<dyn Sink>,
+    shared: Arc<Mutex<Shared>>,
+    source: Option<Source>,
+    next: Option<(Source, u64)>,
+    sink_format: Option<(u32, usize)>,
+    samples: Option<SampleBuffer<i16>>,
+    frames_written: u64,
+    // frames to throw away after an accurate seek landed a bit early
+    skip_frames: u64,
+    paused: bool,
+    gain: f32,
+    equalizer: Filter
+}
+
+impl Playback {
+    fn set_paused(&mut self, paused: bool) {
+        if self.paused != paused {
+            self.paused = paused;
+            self.sink.pause(paused);
+        }
+    }
+    fn end_of_track(&mut self) {
+        self.source = None;
+        self.shared.lock().unwrap().eof = true;
+    }
+    // not the track's fault, so it isn't reported as its end
+    fn output_failed(&mut self) {
+        self.source = None;
+        self.next = None;
+        self.sink_format = None;
+        self.shared.lock().unwrap().output_failed = true;
+    }
+    fn report_position(&mut self) {
+        let delay = self.sink.delay();
+        let mut shared = self.shared.lock().unwrap();
+        shared.frames_heard = self.frames_written.saturating_sub(delay);
+    }
+    fn handle(&mut self, command: Command) {
+        match command {
+            Command::Load(source, generation) => {
+                self.sink.flush();
+                {
+                    let mut shared = self.shared.lock().unwrap();
+                    *shared = Shared { rate: source.rate, generation, ..Shared::default() };
+                }
+                self.source = Some(source);
+                self.next = None;
+                self.frames_written = 0;
+                self.skip_frames = 0;
+                self.set_paused(false);
+            },
+            Command::Preload(source, generation) => {
+                self.next = Some((source, generation));
+            },
+            Command::ClearPreload => {
+                self.next = None;
+            },
+            Command::TogglePause => {
+                let paused = !self.paused;
+                self.set_paused(paused);
+            },
+            // like mplayer, seeking and changing the volume resume playback
+            Command::Seek(secs) => {
+                self.set_paused(false);
+                let source = match &mut self.source {
+                    Some(s) => s,
+                    None => return
+                };
+                let to = SeekTo::Time { time: Time::new(secs.max(0.0) as u64, secs.max(0.0).fract() as f64), track_id: Some(source.track_id) };
+                match source.format.seek(SeekMode::Accurate, to) {
+                    Ok(seeked) => {
+                        source.decoder.reset();
+                        self.frames_written = source.ts_to_frames(seeked.required_ts);
+                        self.skip_frames = self.frames_written.saturating_sub(source.ts_to_frames(seeked.actual_ts));
+                        self.sink.flush();
+                        self.report_position();
+                    },
+                    Err(e) => {
+                        // mplayer moves on to the next track when seeking past the end
+                        log!("native", "seek to {} failed: {:?}", secs, e);
+                        self.sink.flush();
+                        self.end_of_track();
+                    }
+                }
+            },
+            Command::Volume(v) => {
+                self.set_paused(false);
+                self.gain = v as f32 / 100.0 * MAX_GAIN;
+            },
+            Command::Equalizer(bands) => {
+                self.equalizer.set_bands(bands);
+            },
+            Command::Stop => {
+                self.sink.flush();
+                self.source = None;
+                self.next = None;
+            }
+        }
+    }
+    // decodes and plays one packet
+    fn play_packet(&mut self) {
+        let source = match &mut self.source {
+            Some(s) => s,
+            None => return
+        };
+        let packet = match source.format.next_packet() {
+            Ok(p) => p,
+            Err(DecodeError::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                if let Some((next, generation)) = self.next.take() {
+                    // straight on with the next track, the sink never runs dry
+                    *self.shared.lock().unwrap() = Shared { rate: next.rate, generation, ..Shared::default() };
+                    self.source = Some(next);
+                    self.frames_written = 0;
+                    self.skip_frames = 0;
+                    return;
+                }
+                self.sink.drain();
+                self.report_position();
+                self.end_of_track();
+                return;
+            },
+            Err(e) => {
+                error!("native", "could not read packet: {:?}", e);
+                self.end_of_track();
+                return;
+            }
+        };
+        if packet.track_id() != source.track_id {
+            return;
+        }
+        let decoded = match source.decoder.decode(&packet) {
+            Ok(d) => d,
+            Err(DecodeError::DecodeError(e)) => {
+                // a corrupt frame, carry on with the next one
+                log!("native", "skipping undecodable packet: {}", e);
+                return;
+            },
+            Err(e) => {
+                error!("native", "decoding failed: {:?}", e);
+                self.end_of_track();
+                return;
+            }
+        };
+        let spec = *decoded.spec();
+        let channels = spec.channels.count();
+        if self.samples.as_ref().map(|b| b.capacity() < decoded.capacity() * channels).unwrap_or(true) {
+            self.samples = Some(SampleBuffer::new(decoded.capacity() as u64, spec));
+        }
+        let buffer = self.samples.as_mut().unwrap();
+        buffer.copy_interleaved_ref(decoded);
+        let frames = buffer.samples().len() / channels.max(1);
+        let skip = (self.skip_frames as usize).min(frames);
+        self.skip_frames -= skip as u64;
+        let gain = self.gain;
+        let mut mixed: Vec<f32> = buffer.samples()[skip * channels..].iter().map(|s| *s as f32 * gain).collect();
+        self.equalizer.process(&mut mixed, spec.rate, channels);
+        let out: Vec<i16> = mixed.iter()
+            .map(|s| s.max(i16::MIN as f32).min(i16::MAX as f32) as i16)
+            .collect();
+        if self.sink_format != Some((spec.rate, channels)) {
+            if !self.sink.open(spec.rate, channels) {
+                self.output_failed();
+                return;
+            }
+            self.sink_format = Some((spec.rate, channels));
+        }
+        if !self.sink.write(&out) {
+            error!("native", "audio output failed");
+            self.output_failed();
+            return;
+        }
+        self.frames_written += (frames - skip) as u64;
+        self.report_position();
+    }
+    fn run(mut self, commands: Receiver<Command>) {
+        loop {
+            let idle = self.source.is_none() || self.paused;
+            let command = if idle {
+                match commands.recv() {
+                    Ok(c) => Some(c),
+                    Err(_) => return
+                }
+            } else {
+                match commands.try_recv() {
+                    Ok(c) => Some(c),
+                    Err(TryRecvError::Empty) => None,
+                    Err(TryRecvError::Disconnected) => return
+                }
+            };
+            match command {
+                Some(c) => self.handle(c),
+                None => self.play_packet()
+            }
+        }
+    }
+}
+
+pub struct NativeBackend {
+    commands: Sender<Command>,
+    shared: Arc<Mutex<Shared>>,
+    length: f32,
+    loaded: bool,
+    // of the track playing now, and the last one handed to the playback thread
+    generation: u64,
+    issued: u64,
+    preloaded: Option<(u64, f32)>
+}
+
+impl NativeBackend {
+    pub fn new(sink: Box<dyn Sink>) -> NativeBackend {
+        let shared = Arc::new(Mutex::new(Shared::default()));
+        let (commands, rx) = mpsc::channel::<Command>();
+        let playback = Playback { sink, shared: shared.clone(), source: None, next: None, sink_format: None, samples: None, frames_written: 0, skip_frames: 0, paused: false, gain: 0.6 * MAX_GAIN, equalizer: Filter::new(FLAT) };
+        thread::spawn(move || playback.run(rx));
+        NativeBackend { commands, shared, length: 0.0, loaded: false, generation: 0, issued: 0, preloaded: None }
+    }
+    fn send(&self, command: Command) {
+        if self.commands.send(command).is_err() {
+            error!("native", "playback thread is gone");
+        }
+    }
+    fn seconds(&self) -> f32 {
+        let shared = self.shared.lock().unwrap();
+        if shared.rate == 0 || shared.generation != self.generation { 0.0 } else { shared.frames_heard as f32 / shared.rate as f32 }
+    }
+}
+
+impl PlayerBackend for NativeBackend {
+    // the sink is opened by the playback thread, so anything that goes wrong here is down to the file
+    fn load(&mut self, path: &Path, volume: u32) -> Result<(), LoadError> {
+        let (source, length) = match Source::open(path) {
+            Some(s) => s,
+            None => return Err(LoadError::File)
+        };
+        log!("native", "playing {} ({}s)", path.display(), length);
+        self.length = length;
+        self.send(Command::Volume(volume));
+        self.issued += 1;
+        self.generation = self.issued;
+        self.preloaded = None;
+        self.send(Command::Load(source, self.generation));
+        self.loaded = true;
+        Ok(())
+    }
+    fn preload(&mut self, path: &Path) -> bool {
+        if !self.loaded {
+            return false;
+        }
+        let (source, length) = match Source::open(path) {
+            Some(s) => s,
+            None => return false
+        };
+        log!("native", "preloading {}", path.display());
+        self.issued += 1;
+        self.preloaded = Some((self.issued, length));
+        self.send(Command::Preload(source, self.issued));
+        true
+    }
+    fn clear_preload(&mut self) {
+        if self.preloaded.take().is_some() {
+            self.send(Command::ClearPreload);
+        }
+    }
+    fn toggle_pause(&mut self) {
+        self.send(Command::TogglePause);
+    }
+    fn seek_relative(&mut self, secs: f32) {
+        let to = self.seconds() + secs;
+        self.send(Command::Seek(to));
+    }
+    fn seek_absolute(&mut self, secs: f32) {
+        self.send(Command::Seek(secs));
+    }
+    fn set_volume(&mut self, volume: u32) {
+        self.send(Command::Volume(volume));
+    }
+    fn set_equalizer(&mut self, bands: &Bands) {
+        self.send(Command::Equalizer(*bands));
+    }
+    fn position(&mut self) -> Option<f32> {
+        if !self.loaded {
+            return None;
+        }
+        Some(self.seconds())
+    }
+    fn length(&self) -> f32 {
+        self.length
+    }
+    fn poll_event(&mut self) -> Option<PlayerEvent> {
+        if !self.loaded {
+            return None;
+        }
+        let mut shared = self.shared.lock().unwrap();
+        if let Some((generation, length)) = self.preloaded {
+            if shared.generation == generation {
+                self.generation = generation;
+                self.length = length;
+                self.preloaded = None;
+                return Some(PlayerEvent::Advanced);
+            }
+        }
+        if shared.output_failed && shared.generation == self.generation {
+            shared.output_failed = false;
+            self.loaded = false;
+            self.preloaded = None;
+            return Some(PlayerEvent::OutputFailed);
+        }
+        if shared.eof && shared.generation == self.generation {
+            shared.eof = false;
+            self.loaded = false;
+            return Some(PlayerEvent::EndOfTrack);
+        }
+        None
+    }
+    fn stop(&mut self) {
+        if self.loaded {
+            self.send(Command::Stop);
+            self.loaded = false;
+            self.preloaded = None;
+        }
+    }
+}
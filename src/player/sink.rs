@@ -0,0 +1,281 @@
+// player/sink.rs
+// Audio outputs for the native backend, they take interleaved 16-bit samples
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{Write, Seek, SeekFrom};
+use std::path::PathBuf;
+use std::thread::sleep;
+use std::time::Duration;
+
+use byteorder::{LittleEndian, WriteBytesExt};
+use serde::{Serialize, Deserialize};
+
+use crate::{log, error};
+use crate::read_config::{root, BuckNativeConfig};
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum NativeOutput {
+    Alsa,
+    // discards the audio in real time, for trying things out without a sound card
+    Null,
+    // writes everything played to a .wav file, for checking the decoded output
+    Wav
+}
+
+impl Default for NativeOutput {
+    fn default() -> NativeOutput {
+        NativeOutput::Alsa
+    }
+}
+
+pub trait Sink: Send {
+    // called before the first write and whenever the format of the audio changes
+    fn open(&mut self, rate: u32, channels: usize) -> bool;
+    // blocks until the output has room, like a sound card does
+    fn write(&mut self, samples: &[i16]) -> bool;
+    // frames written but not heard yet
+    fn delay(&mut self) -> u64 { 0 }
+    fn pause(&mut self, _paused: bool) {}
+    // drops buffered audio, after seeks and track changes
+    fn flush(&mut self) {}
+    // blocks until buffered audio has been heard
+    fn drain(&mut self) {}
+}
+
+pub struct NullSink {
+    rate: u32,
+    channels: usize,
+    realtime: bool
+}
+
+impl NullSink {
+    pub fn new(realtime: bool) -> NullSink {
+        NullSink { rate: 44100, channels: 2, realtime }
+    }
+}
+
+impl Sink for NullSink {
+    fn open(&mut self, rate: u32, channels: usize) -> bool {
+        self.rate = rate;
+        self.channels = channels;
+        true
+    }
+    fn write(&mut self, samples: &[i16]) -> bool {
+        if self.realtime {
+            let frames = samples.len() / self.channels.max(1);
+            sleep(Duration::from_secs_f64(frames as f64 / self.rate.max(1) as f64));
+        }
+        true
+    }
+}
+
+pub struct WavSink {
+    path: PathBuf,
+    file: Option<File>,
+    format: (u32, usize),
+    data_bytes: u32
+}
+
+impl WavSink {
+    pub fn new(path: PathBuf) -> WavSink {
+        WavSink { path, file: None, format: (0, 0), data_bytes: 0 }
+    }
+    fn write_header(file: &mut File, rate: u32, channels: usize, data_bytes: u32) -> std::io::Result<()> {
+        let block_align = channels as u16 * 2;
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(b"RIFF")?;
+        file.write_u32::<LittleEndian>(36 + data_bytes)?;
+        file.write_all(b"WAVEfmt ")?;
+        file.write_u32::<LittleEndian>(16)?;
+        file.write_u16::<LittleEndian>(1)?;
+        file.write_u16::<LittleEndian>(channels as u16)?;
+        file.write_u32::<LittleEndian>(rate)?;
+        file.write_u32::<LittleEndian>(rate * block_align as u32)?;
+        file.write_u16::<LittleEndian>(block_align)?;
+        file.write_u16::<LittleEndian>(16)?;
+        file.write_all(b"data")?;
+        file.write_u32::<LittleEndian>(data_bytes)?;
+        file.seek(SeekFrom::End(0))?;
+        Ok(())
+    }
+    fn finish(&mut self) {
+        let (rate, channels) = self.format;
+        if let Some(file) = &mut self.file {
+            if let Err(e) = WavSink::write_header(file, rate, channels, self.data_bytes) {
+                error!("sink", "could not finish {}: {:?}", self.path.display(), e);
+            }
+        }
+    }
+}
+
+impl Sink for WavSink {
+    fn open(&mut self, rate: u32, channels: usize) -> bool {
+        if self.file.is_some() {
+            // a .wav has a single format, so later tracks have to match the first one
+            if self.format != (rate, channels) {
+                error!("sink", "{} Hz/{} channels does not fit into {}", rate, channels, self.path.display());
+                return false;
+            }
+            return true;
+        }
+        let mut file = match File::create(&self.path) {
+            Ok(f) => f,
+            Err(e) => {
+                error!("sink", "could not create {}: {:?}", self.path.display(), e);
+                return false;
+            }
+        };
+        if WavSink::write_header(&mut file, rate, channels, 0).is_err() {
+            return false;
+        }
+        log!("sink", "writing audio to {}", self.path.display());
+        self.format = (rate, channels);
+        self.file = Some(file);
+        true
+    }
+    fn write(&mut self, samples: &[i16]) -> bool {
+        let file = match &mut self.file {
+            Some(f) => f,
+            None => return false
+        };
+        let mut bytes = Vec::with_capacity(samples.len() * 2);
+        for s in samples {
+            bytes.extend_from_slice(&s.to_le_bytes());
+        }
+        if file.write_all(&bytes).is_err() {
+            return false;
+        }
+        self.data_bytes += bytes.len() as u32;
+        true
+    }
+    fn drain(&mut self) {
+        self.finish();
+    }
+}
+
+impl Drop for WavSink {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+#[cfg(feature = "alsa")]
+pub struct AlsaSink {
+    device: String,
+    pcm: Option<alsa::PCM>,
+    format: (u32, usize),
+    can_pause: bool
+}
+
+#[cfg(feature = "alsa")]
+impl AlsaSink {
+    pub fn new(device: String) -> AlsaSink {
+        AlsaSink { device, pcm: None, format: (0, 0), can_pause: false }
+    }
+    fn open_pcm(&self, rate: u32, channels: usize) -> alsa::Result<(alsa::PCM, bool)> {
+        use alsa::pcm::{HwParams, Format, Access};
+        let pcm = alsa::PCM::new(&self.device, alsa::Direction::Playback, false)?;
+        let can_pause;
+        {
+            let hwp = HwParams::any(&pcm)?;
+            hwp.set_channels(channels as u32)?;
+            hwp.set_rate(rate, alsa::ValueOr::Nearest)?;
+            hwp.set_format(Format::s16())?;
+            hwp.set_access(Access::RWInterleaved)?;
+            // half a second of buffer rides out the UI redrawing the screen
+            hwp.set_buffer_time_near(500_000, alsa::ValueOr::Nearest)?;
+            pcm.hw_params(&hwp)?;
+            can_pause = hwp.can_pause();
+        }
+        Ok((pcm, can_pause))
+    }
+}
+
+#[cfg(feature = "alsa")]
+impl Sink for AlsaSink {
+    fn open(&mut self, rate: u32, channels: usize) -> bool {
+        if self.pcm.is_some() && self.format == (rate, channels) {
+            return true;
+        }
+        if let Some(pcm) = self.pcm.take() {
+            let _ = pcm.drain();
+        }
+        match self.open_pcm(rate, channels) {
+            Ok((pcm, can_pause)) => {
+                log!("sink", "opened {} at {} Hz, {} channels", self.device, rate, channels);
+                self.pcm = Some(pcm);
+                self.format = (rate, channels);
+                self.can_pause = can_pause;
+                true
+            },
+            Err(e) => {
+                error!("sink", "could not open {}: {:?}", self.device, e);
+                false
+            }
+        }
+    }
+    fn write(&mut self, samples: &[i16]) -> bool {
+        let pcm = match &self.pcm {
+            Some(p) => p,
+            None => return false
+        };
+        let channels = self.format.1.max(1);
+        let mut written = 0;
+        while written < samples.len() {
+            match pcm.io_i16().and_then(|io| io.writei(&samples[written..])) {
+                Ok(frames) => written += frames * channels,
+                Err(e) => {
+                    // underruns (e.g. after a pause without hardware support) just need the device prepared again
+                    if let Err(e) = pcm.try_recover(e, true) {
+                        error!("sink", "write failed: {:?}", e);
+                        return false;
+                    }
+                }
+            }
+        }
+        true
+    }
+    fn delay(&mut self) -> u64 {
+        self.pcm.as_ref().and_then(|p| p.delay().ok()).map(|d| d.max(0) as u64).unwrap_or(0)
+    }
+    fn pause(&mut self, paused: bool) {
+        if let Some(pcm) = &self.pcm {
+            if self.can_pause {
+                let _ = pcm.pause(paused);
+            } else if paused {
+                let _ = pcm.drop();
+                let _ = pcm.prepare();
+            }
+        }
+    }
+    fn flush(&mut self) {
+        if let Some(pcm) = &self.pcm {
+            let _ = pcm.drop();
+            let _ = pcm.prepare();
+        }
+    }
+    fn drain(&mut self) {
+        if let Some(pcm) = &self.pcm {
+            let _ = pcm.drain();
+            let _ = pcm.prepare();
+        }
+    }
+}
+
+pub fn new_sink(config: &BuckNativeConfig) -> Box<dyn Sink> {
+    match config.output {
+        #[cfg(feature = "alsa")]
+        NativeOutput::Alsa => Box::new(AlsaSink::new(config.device.clone())),
+        // silently playing into the void would look like a hang, so say so on screen and stop
+        #[cfg(not(feature = "alsa"))]
+        NativeOutput::Alsa => {
+            error!("fatal", "\"player\": \"native\" with \"output\": \"alsa\" needs a build with the alsa feature");
+            crate::process_runner::quick_write(2, "* This build of Buck can't play through ALSA");
+            crate::process_runner::quick_write(3, "   Set \"player\" to \"mplayer\" in config.json");
+            std::process::exit(1);
+        },
+        NativeOutput::Null => Box::new(NullSink::new(true)),
+        NativeOutput::Wav => Box::new(WavSink::new(PathBuf::from(&config.wav_path)))
+    }
+}
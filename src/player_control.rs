@@ -0,0 +1,827 @@
+// player_control.rs
+// The player control thread, everything between the UI's control messages and the playback backend
+
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::sync::mpsc::{Sender, Receiver};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use crate::{Track, ControlMsg, log};
+use crate::{btctl_keepalive, equalizer, failures, history, player_state, sleep_timer, speeds};
+use crate::bookmarks::Bookmarks;
+use crate::equalizer::EqPreset;
+use crate::failures::Failures;
+use crate::history::PlayHistory;
+use crate::play_order::{PlayOrder, QueueOp};
+use crate::player::{PlayerBackend, PlayerEvent, LoadError};
+use crate::player_state::PlayerState;
+use crate::process_runner::quick_write;
+use crate::read_config::{root, BuckConfig};
+use crate::replay_gain::{ReplayGainMode, Leveled};
+use crate::sleep_timer::{SleepTimer, SleepSetting};
+use crate::speeds::{Speeds, SpeedScope};
+use crate::track_code::{TrackCode, TrackNumbering};
+
+// how long the loop waits for a control message before checking on playback
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+pub struct PlayerControl {
+    tracks: Vec<Track>,
+    player: Leveled,
+    // the outgoing track of a crossfade plays on here while it fades away
+    fading_player: Option<Leveled>,
+    replies: Sender<ControlMsg>,
+    numbering: TrackNumbering,
+    crossfade_secs: f32,
+    eq_presets: Vec<EqPreset>,
+    replay_gain_mode: ReplayGainMode,
+    replay_gain_preamp: f32,
+    bookmarks: Bookmarks,
+    speeds: Speeds,
+    play_history: PlayHistory,
+    // what the backend is playing at, which stays 1.0 on backends without speed control
+    speed: f32,
+    play_order: PlayOrder,
+    failures: Failures,
+    btonly_keepalive: Option<btctl_keepalive::BTKeepAlive>,
+    currently_playing: u32,
+    currently_paused: bool,
+    length_of_song: f32,
+    last_time_pos: f32,
+    current_volume: u32,
+    play_counted: bool,
+    eq_preset: EqPreset,
+    saved_state: PlayerState,
+    last_state_save: Instant,
+    sleep_timer: SleepTimer,
+    sleep_label: String,
+    // (track, volume) last set by the fade, and whether playback stopped with the volume at nothing
+    // (faded out, or a track loaded silent to wait on)
+    fade: Option<(u32, u32)>,
+    paused_silent: bool,
+    // when the crossfade started, how long it takes and the volume the incoming track was last set to
+    crossfade: Option<(Instant, f32, u32)>,
+    // the next track wouldn't load for a crossfade, it comes in by a plain track change instead
+    crossfade_refused: bool
+}
+
+impl PlayerControl {
+    // picks up where saved_state left off once started
+    pub fn new(tracks: Vec<Track>, config: &BuckConfig, player: Leveled, fading_player: Option<Leveled>, replies: Sender<ControlMsg>,
+               saved_state: PlayerState, bookmarks: Bookmarks, speeds: Speeds, play_history: PlayHistory) -> PlayerControl {
+        let restored_track = saved_state.track.as_ref().and_then(|p| tracks.iter().position(|t| &t.path == p));
+        let play_order = PlayOrder::new(&tracks, &saved_state.modes, restored_track.unwrap_or(0) as u32);
+        let failures = Failures::new(&tracks);
+        let eq_presets = equalizer::presets(&config.equalizer.presets);
+        let eq_preset = equalizer::find(&eq_presets, &saved_state.equalizer).unwrap_or(&eq_presets[0]).clone();
+        PlayerControl {
+            currently_playing: play_order.current(),
+            currently_paused: false,
+            length_of_song: 0.0,
+            last_time_pos: if restored_track.is_some() { saved_state.position } else { 0.0 },
+            current_volume: saved_state.volume,
+            play_counted: false,
+            tracks,
+            player,
+            fading_player,
+            replies,
+            numbering: config.toc.numbering,
+            crossfade_secs: config.crossfade_secs,
+            eq_presets,
+            replay_gain_mode: config.replay_gain.mode,
+            replay_gain_preamp: config.replay_gain.preamp_db,
+            bookmarks,
+            speeds,
+            play_history,
+            speed: 1.0,
+            play_order,
+            failures,
+            btonly_keepalive: None,
+            eq_preset,
+            saved_state,
+            last_state_save: Instant::now(),
+            sleep_timer: SleepTimer::new(),
+            sleep_label: String::new(),
+            fade: None,
+            paused_silent: false,
+            crossfade: None,
+            crossfade_refused: false
+        }
+    }
+
+    // loads the track playing when the last run ended, blocks until something plays
+    pub fn start(&mut self) {
+        // the equalizer outlasts tracks, so it goes on before the first one
+        self.player.set_equalizer(&self.eq_preset.bands);
+        if let Some(fading) = self.fading_player.as_mut() { fading.set_equalizer(&self.eq_preset.bands); }
+        if !self.spawn_player(self.current_volume) {
+            return;
+        }
+        let startup_speed = self.speeds.speed_for(&self.tracks[self.currently_playing as usize].path);
+        self.speed = self.player.set_speed(startup_speed);
+        self.replies.send(ControlMsg::SPEED(self.speed));
+        if self.last_time_pos > 0.0 {
+            log!("player-control", "resuming track {} at {}", self.currently_playing, self.last_time_pos);
+            self.player.seek_absolute(self.last_time_pos);
+        }
+        let paused = self.saved_state.paused;
+        self.set_currently_paused(paused);
+        if paused {
+            self.player.toggle_pause(); //start paused, like it was left
+        }
+    }
+
+    pub fn run(mut self, rx: Receiver<ControlMsg>) {
+        log!("player-control", "entering event loop...");
+        loop {
+            // check for control messages
+            if let Ok(m) = rx.recv_timeout(POLL_INTERVAL) {
+                self.handle(m);
+            }
+            self.tick();
+        }
+    }
+
+    fn set_currently_paused(&mut self, v: bool) {
+        self.currently_paused = v;
+        // whatever gets playback going again after it stopped silent brings the volume back with it
+        if !v && self.paused_silent {
+            self.paused_silent = false;
+            self.player.set_volume(self.current_volume);
+        }
+        self.replies.send(ControlMsg::PAUSED(v));
+    }
+
+    // tracks from the same album follow each other without a crossfade, so gapless albums stay that way
+    fn crossfades(&self, from: u32, to: u32) -> bool {
+        self.crossfade_secs > 0.0 && self.tracks[from as usize].album_number != self.tracks[to as usize].album_number
+    }
+
+    // gapless backends start on the next track as soon as the current one runs out, unless the
+    // sleep timer runs out first (that track gets loaded silent and paused on instead)
+    fn queue_following(&mut self) {
+        match self.play_order.auto_next(&self.tracks) {
+            Some(n) if !self.crossfades(self.currently_playing, n) && !self.sleep_timer.ends_at_change(&self.tracks, self.currently_playing, n) => {
+                self.player.preload(&self.tracks[n as usize].path);
+            },
+            _ => self.player.clear_preload()
+        }
+    }
+
+    // long files pick up where they were left, at the speed they were listened to
+    fn resume_at(&mut self, i: u32) -> f32 {
+        let speed = self.speeds.speed_for(&self.tracks[i as usize].path);
+        self.speed = self.player.set_speed(speed);
+        self.replies.send(ControlMsg::SPEED(self.speed));
+        match self.bookmarks.position(&self.tracks[i as usize].path) {
+            Some(pos) => {
+                log!("player-control", "resuming track {} at its bookmark {}", i, pos);
+                self.player.seek_absolute(pos);
+                pos
+            },
+            None => 0.0
+        }
+    }
+
+    // loudness normalisation, folded into every volume the backend gets
+    fn level_of(&self, i: u32) -> f32 {
+        self.tracks[i as usize].replay_gain.level(self.replay_gain_mode, self.play_order.mode(), self.replay_gain_preamp)
+    }
+
+    // loads the current track, moving on past files that won't play. False, and paused, when a
+    // whole pass through the order found nothing that plays
+    fn spawn_player(&mut self, volume: u32) -> bool {
+        quick_write(8, "* Spawning player");
+        let mut attmpt_binary: bool = false;
+        let mut attempts: u32 = 0;
+        // tried in turn after the track asked for, without taking anything off the queue
+        let mut candidates = self.play_order.upcoming().into_iter();
+        let mut i = self.currently_playing;
+        loop {
+            let path = &self.tracks[i as usize].path;
+            // given up on before, passed over without trying it again
+            let mut skip = self.failures.is_unplayable(path);
+            if !skip {
+                let level = self.level_of(i);
+                self.player.set_level(level);
+                match self.player.load(path, volume) {
+                    Ok(()) => break,
+                    Err(LoadError::File) => {
+                        attempts += 1;
+                        if attempts >= failures::MAX_ATTEMPTS {
+                            self.failures.give_up(path, attempts);
+                            quick_write(8, &format!("* Skipped unplayable track: {}", self.tracks[i as usize].title));
+                            skip = true;
+                        } else {
+                            quick_write(8, &format!("* Could not play {}, trying again", self.tracks[i as usize].title));
+                            sleep(Duration::from_secs(1));
+                        }
+                    },
+                    Err(LoadError::Output) => {
+                        if attmpt_binary == false { attmpt_binary = true; }
+                        else { attmpt_binary = false; }
+                        quick_write(8, &format!("{} Player spawn failed! Retrying after 5 seconds", if attmpt_binary { "|" } else { "=" }));
+                        quick_write(9, "   (are you connected to a Bluetooth speaker?)");
+                        sleep(Duration::from_secs(5));
+                    }
+                }
+            }
+            if skip {
+                attempts = 0;
+                match candidates.next() {
+                    Some(t) => i = t,
+                    None => {
+                        log!("player-control", "none of the tracks will play, pausing");
+                        quick_write(8, "* None of the tracks will play");
+                        self.set_currently_paused(true);
+                        return false;
+                    }
+                }
+            }
+        }
+        if i != self.currently_playing {
+            self.play_order.skip_to(&self.tracks, i);
+            self.currently_playing = i;
+        }
+        self.track_loaded();
+        true
+    }
+
+    // the current track is loaded on self.player
+    fn track_loaded(&mut self) {
+        // handle Bluetooth keep-alive for btonly devices
+        if cfg!(feature = "btonly") {
+            self.btonly_keepalive = Some(btctl_keepalive::BTKeepAlive::spawn());
+        }
+        self.crossfade_refused = false;
+        // notify UI
+        self.replies.send(ControlMsg::NEWTRACK(self.currently_playing));
+        self.length_of_song = self.player.length();
+        self.replies.send(ControlMsg::LENGTH(self.length_of_song));
+        self.queue_following();
+    }
+
+    // a new track picked by Next, Previous or by hand, the current one is left where it is
+    fn change_track(&mut self) {
+        if !self.spawn_player(self.current_volume) {
+            return;
+        }
+        self.play_counted = false;
+        self.last_time_pos = self.resume_at(self.currently_playing);
+        self.set_currently_paused(false);
+    }
+
+    fn keep_bluetooth_awake(&mut self) {
+        if cfg!(feature = "btonly") {
+            if let Some(ka) = &mut self.btonly_keepalive { ka.scan_on_temp(); }
+        }
+    }
+
+    pub fn handle(&mut self, m: ControlMsg) {
+        // track codes are just another way of picking a track
+        let m = match m {
+            ControlMsg::PLAYCODE(code) => {
+                log!("player-control", "resolving track code {}", code);
+                ControlMsg::SETTRACK(code.resolve(&self.tracks).unwrap_or(u32::MAX))
+            },
+            m => m
+        };
+        // anything done to playback cuts a crossfade short
+        if self.crossfade.is_some() && matches!(m, ControlMsg::PAUSE() | ControlMsg::SEEK_FORWARD(_) | ControlMsg::SEEK_BACKWARD(_) | ControlMsg::SETPOS(_) | ControlMsg::NEXT() | ControlMsg::PREV() | ControlMsg::SETTRACK(_) | ControlMsg::SETVOL(_)) {
+            log!("player-control", "crossfade cut short");
+            self.crossfade = None;
+            if let Some(fading) = self.fading_player.as_mut() { fading.stop(); }
+            self.player.set_volume(self.current_volume);
+        }
+        match m {
+            ControlMsg::PAUSE() => {
+                if self.paused_silent && self.currently_paused {
+                    // setting the volume back resumes too
+                    log!("player-control", "player: waking up at volume {}", self.current_volume);
+                    self.set_currently_paused(false);
+                } else {
+                    let new_pause_state = !self.currently_paused;
+                    self.set_currently_paused(new_pause_state);
+                    log!("player-control", "player: {}", "pause");
+                    self.player.toggle_pause();
+                }
+            },
+            ControlMsg::SEEK_FORWARD(secs) => {
+                self.keep_bluetooth_awake();
+                self.set_currently_paused(false);
+                log!("player-control", "player: {}", "seek");
+                self.player.seek_relative(secs);
+            },
+            ControlMsg::SEEK_BACKWARD(secs) => {
+                self.keep_bluetooth_awake();
+                self.set_currently_paused(false);
+                log!("player-control", "player: {}", "seek");
+                self.player.seek_relative(-secs);
+            },
+            ControlMsg::SETPOS(v) => {
+                self.keep_bluetooth_awake();
+                self.set_currently_paused(false);
+                log!("player-control", "player: {} {}", "seek", v);
+                self.player.seek_absolute(self.length_of_song * v);
+            },
+            ControlMsg::NEXT() => {
+                self.bookmarks.leave(&self.tracks[self.currently_playing as usize].path, self.last_time_pos, self.length_of_song);
+                self.player.stop();
+                self.currently_playing = self.play_order.next();
+                log!("player-control", "-next- removing old player, currently playing is now {}", self.currently_playing);
+                self.change_track();
+            },
+            ControlMsg::PREV() => {
+                self.bookmarks.leave(&self.tracks[self.currently_playing as usize].path, self.last_time_pos, self.length_of_song);
+                self.player.stop();
+                self.currently_playing = self.play_order.prev(&self.tracks);
+                log!("player-control", "-prev- removing old player, currently playing is now {}", self.currently_playing);
+                self.change_track();
+            },
+            ControlMsg::SETVOL(v) => {
+                log!("player-control", "player: volume {}", v);
+                self.current_volume = v;
+                self.paused_silent = false;
+                self.set_currently_paused(false);
+                self.player.set_volume(v);
+                self.replies.send(ControlMsg::VOL(self.current_volume));
+            },
+            ControlMsg::SETTRACK(t) => {
+                if t < self.tracks.len() as u32 {
+                    log!("player-control", "-set- removing old player, currently playing is now {}", self.currently_playing);
+                    self.bookmarks.leave(&self.tracks[self.currently_playing as usize].path, self.last_time_pos, self.length_of_song);
+                    self.player.stop();
+                    self.play_order.jump_to(&self.tracks, t);
+                    self.currently_playing = t;
+                    self.change_track();
+                } else {
+                    log!("player-control", "-set- track number received is out of range ({})! ignoring..", t);
+                    println!("out of range");
+                }
+            },
+            ControlMsg::SETSHUFFLE(_) | ControlMsg::CYCLESHUFFLE() => {
+                let mode = match m {
+                    ControlMsg::SETSHUFFLE(mode) => mode,
+                    _ => self.play_order.mode().cycle()
+                };
+                log!("player-control", "shuffle {}", mode.label());
+                self.play_order.set_mode(&self.tracks, mode, self.currently_playing);
+                // auto normalisation goes by album or by track depending on the shuffle
+                let level = self.level_of(self.currently_playing);
+                self.player.set_level(level);
+                if !self.currently_paused { self.player.refresh_volume(); }
+                // whatever was queued up to follow belongs to the old order
+                self.queue_following();
+                self.replies.send(ControlMsg::SHUFFLE(mode));
+            },
+            ControlMsg::SETREPEAT(_) | ControlMsg::CYCLEREPEAT() => {
+                let mode = match m {
+                    ControlMsg::SETREPEAT(mode) => mode,
+                    _ => self.play_order.repeat().cycle()
+                };
+                log!("player-control", "repeat {}", mode.label());
+                self.play_order.set_repeat(mode);
+                self.queue_following();
+                self.replies.send(ControlMsg::REPEAT(mode));
+            },
+            ControlMsg::GETREPEAT() => {
+                self.replies.send(ControlMsg::REPEAT(self.play_order.repeat()));
+            },
+            ControlMsg::SETSLEEP(_) | ControlMsg::CYCLESLEEP() => {
+                let setting = match m {
+                    ControlMsg::SETSLEEP(setting) => setting,
+                    _ => self.sleep_timer.setting().cycle()
+                };
+                log!("player-control", "sleep timer {:?}", setting);
+                self.sleep_timer.set(setting);
+                if self.fade.take().is_some() && !self.currently_paused {
+                    self.player.set_volume(self.current_volume);
+                }
+                // the label goes out with the next position check
+                self.sleep_label.clear();
+                self.queue_following();
+            },
+            ControlMsg::GETSLEEP() => {
+                self.sleep_label.clear();
+            },
+            ControlMsg::SETSPEED(..) | ControlMsg::CYCLESPEED() => {
+                let path = &self.tracks[self.currently_playing as usize].path;
+                let (speed, scope) = match m {
+                    ControlMsg::SETSPEED(speed, scope) => (speed, scope),
+                    _ => (speeds::next_step(self.speeds.speed_for(path)), SpeedScope::Collection)
+                };
+                self.speed = self.player.set_speed(speed);
+                if self.speed == speed {
+                    log!("player-control", "speed {} for the {:?}", speed, scope);
+                    self.speeds.set(path, speed, scope);
+                } else {
+                    // not remembered, it would only take effect after switching backends
+                    log!("player-control", "this player can't change speed, staying at {}", self.speed);
+                    quick_write(8, "* This player only plays at normal speed");
+                }
+                self.replies.send(ControlMsg::SPEED(self.speed));
+            },
+            ControlMsg::SETEQ(_) | ControlMsg::CYCLEEQ() => {
+                let preset = match m {
+                    ControlMsg::SETEQ(name) => equalizer::find(&self.eq_presets, &name),
+                    _ => self.eq_presets.iter().cycle().skip_while(|p| p.name != self.eq_preset.name).nth(1)
+                };
+                match preset {
+                    Some(preset) => {
+                        log!("player-control", "equalizer {}", preset.name);
+                        self.eq_preset = preset.clone();
+                        self.player.set_equalizer(&self.eq_preset.bands);
+                        if let Some(fading) = self.fading_player.as_mut() { fading.set_equalizer(&self.eq_preset.bands); }
+                    },
+                    None => {
+                        log!("player-control", "no such equalizer preset");
+                    }
+                }
+                self.replies.send(ControlMsg::EQ(self.eq_preset.name.clone()));
+            },
+            ControlMsg::FAILURES(answer) => {
+                answer.send(self.failures.report());
+            },
+            ControlMsg::GETEQ() => {
+                self.replies.send(ControlMsg::EQ(self.eq_preset.name.clone()));
+            },
+            ControlMsg::GETSPEED() => {
+                self.replies.send(ControlMsg::SPEED(self.speed));
+            },
+            ControlMsg::QUEUE(op, answer) => {
+                log!("player-control", "queue {:?}", op);
+                let tracks = &self.tracks;
+                let numbering = self.numbering;
+                let describe = |t: u32| {
+                    let track = &tracks[t as usize];
+                    format!("{} {} - {}", TrackCode::of(track, t as usize, numbering), track.title, track.artist)
+                };
+                let play_order = &mut self.play_order;
+                let text = match op {
+                    QueueOp::Add(target) | QueueOp::PlayNext(target) => match target.resolve(tracks) {
+                        Some(t) => {
+                            if let QueueOp::PlayNext(_) = op { play_order.play_next(&t); } else { play_order.enqueue(&t); }
+                            format!("queued {} track{}, {} in the queue", t.len(), if t.len() == 1 { "" } else { "s" }, play_order.queued().len())
+                        },
+                        None => String::from("no such track")
+                    },
+                    QueueOp::Remove(n) => match play_order.remove_queued(n - 1) {
+                        Some(t) => format!("removed {}", describe(t)),
+                        None => format!("the queue has no entry {}", n)
+                    },
+                    QueueOp::Clear => {
+                        play_order.clear_queue();
+                        String::from("queue cleared")
+                    },
+                    QueueOp::List => {
+                        if play_order.queued().is_empty() {
+                            String::from("the queue is empty")
+                        } else {
+                            play_order.queued().iter().enumerate().map(|(i, &t)| format!("{}. {}", i + 1, describe(t))).collect::<Vec<String>>().join("\n")
+                        }
+                    }
+                };
+                answer.send(text);
+                self.queue_following();
+            },
+            ControlMsg::GETSHUFFLE() => {
+                self.replies.send(ControlMsg::SHUFFLE(self.play_order.mode()));
+            },
+            ControlMsg::GETVOL() => {
+                log!("player-control", "getvol");
+                self.replies.send(ControlMsg::VOL(self.current_volume));
+            },
+            ControlMsg::GETCURRENTTRACK() => {
+                log!("player-control", "getcurrenttrack");
+                self.replies.send(ControlMsg::CURRENTTRACK(self.currently_playing));
+            },
+            ControlMsg::GETCURRENTTRACKLENGTH() => {
+                log!("player-control", "getcurrenttracklength");
+                self.replies.send(ControlMsg::LENGTH(self.length_of_song));
+            },
+            ControlMsg::GETTRACKINFO(t) => {
+                log!("player-control", "gettrackinfo");
+                self.replies.send(ControlMsg::TRACKINFO(self.tracks[t as usize].clone()));
+            },
+            ControlMsg::UIOPENED() => {
+                log!("player-control", "ui opened");
+                // handle Bluetooth keep-alive for btonly devices
+                if cfg!(feature = "btonly") {
+                    self.btonly_keepalive = Some(btctl_keepalive::BTKeepAlive::spawn());
+                    if self.currently_paused {
+                        log!("player-control", "-uiopen,restart- removing old player, currently playing is now {}", self.currently_playing);
+                        self.player.stop();
+                        if self.spawn_player(self.current_volume) {
+                            self.player.seek_absolute(self.last_time_pos);
+                            self.player.toggle_pause(); //resume paused state
+                        }
+                    }
+                }
+            },
+            ControlMsg::UIHIDDEN() => {
+                log!("player-control", "ui hidden");
+                // handle Bluetooth keep-alive for btonly devices
+                if cfg!(feature = "btonly") && self.currently_paused {
+                    self.btonly_keepalive = None;
+                }
+            },
+            _ => {}
+        }
+    }
+
+    // everything checked on every pass of the loop, whether a message came in or not
+    pub fn tick(&mut self) {
+        // check if song has finished playing
+        let mut fall_asleep = false;
+        match self.player.poll_event() {
+            Some(PlayerEvent::EndOfTrack) => {
+                let previous = self.currently_playing;
+                self.bookmarks.finished(&self.tracks[self.currently_playing as usize].path);
+                let following = self.play_order.advance(&self.tracks);
+                self.currently_playing = match following {
+                    Some(t) => t,
+                    None => {
+                        let first = self.play_order.first().unwrap_or(previous);
+                        self.play_order.jump_to(&self.tracks, first);
+                        first
+                    }
+                };
+                log!("player-control", "yes! moving to next track {}", self.currently_playing);
+                fall_asleep = self.sleep_timer.ends_at_change(&self.tracks, previous, self.currently_playing);
+                // a track that is only loaded to be paused on starts silent, so it doesn't blare out before the pause
+                let waits = following.is_none() || fall_asleep;
+                if self.spawn_player(if waits { 0 } else { self.current_volume }) {
+                    self.play_counted = false;
+                    self.last_time_pos = self.resume_at(self.currently_playing);
+                    if following.is_none() {
+                        // repeat is off and that was the last track, wait on the first one
+                        log!("player-control", "end of the play order, pausing");
+                        self.player.toggle_pause();
+                        self.set_currently_paused(true);
+                    }
+                    self.paused_silent = waits;
+                }
+            },
+            Some(PlayerEvent::Advanced) => {
+                let previous = self.currently_playing;
+                self.bookmarks.finished(&self.tracks[self.currently_playing as usize].path);
+                self.currently_playing = self.play_order.advance(&self.tracks).unwrap_or(self.currently_playing);
+                log!("player-control", "gapless, now playing track {}", self.currently_playing);
+                self.replies.send(ControlMsg::NEWTRACK(self.currently_playing));
+                self.length_of_song = self.player.length();
+                self.replies.send(ControlMsg::LENGTH(self.length_of_song));
+                let level = self.level_of(self.currently_playing);
+                self.player.set_level(level);
+                self.player.refresh_volume();
+                self.queue_following();
+                self.play_counted = false;
+                self.last_time_pos = self.resume_at(self.currently_playing);
+                fall_asleep = self.sleep_timer.ends_at_change(&self.tracks, previous, self.currently_playing);
+            },
+            Some(PlayerEvent::OutputFailed) => {
+                // nothing wrong with the track, it picks up where it stopped once there is somewhere to play it
+                let position = self.last_time_pos;
+                log!("player-control", "audio output lost at {} of track {}", position, self.currently_playing);
+                quick_write(8, "= Audio output lost! Retrying after 5 seconds");
+                quick_write(9, "   (are you connected to a Bluetooth speaker?)");
+                sleep(Duration::from_secs(5));
+                if self.spawn_player(self.current_volume) {
+                    self.player.seek_absolute(position);
+                    self.last_time_pos = position;
+                }
+            },
+            None => {}
+        }
+        // write down where we are, straight away when a setting changed
+        let state = PlayerState { track: Some(self.tracks[self.currently_playing as usize].path.clone()), position: self.last_time_pos, volume: self.current_volume, paused: self.currently_paused, modes: self.play_order.modes(), equalizer: self.eq_preset.name.clone() };
+        if !state.same_settings(&self.saved_state) || (!self.currently_paused && self.last_state_save.elapsed() >= player_state::SAVE_INTERVAL) {
+            state.save();
+            self.saved_state = state;
+            self.last_state_save = Instant::now();
+        }
+        // check song current play position
+        if !self.currently_paused {
+            if let Some(time_pos) = self.player.position() {
+                self.last_time_pos = time_pos;
+                self.replies.send(ControlMsg::POS(time_pos));
+                if !self.play_counted && history::listened_enough(time_pos, self.length_of_song) {
+                    self.play_counted = true;
+                    self.play_history.record_play(&self.tracks[self.currently_playing as usize].path);
+                }
+            }
+        }
+        // crossfade, the next track starts quietly on a fresh backend while this one winds down
+        if let (true, Some(following)) = (self.fading_player.is_some(), self.play_order.auto_next(&self.tracks)) {
+            let left = self.length_of_song - self.last_time_pos;
+            if self.crossfade.is_none() && !self.crossfade_refused && !self.currently_paused && self.length_of_song > 0.0 && left <= self.crossfade_secs
+                && self.crossfades(self.currently_playing, following) && !self.sleep_timer.ends_at_change(&self.tracks, self.currently_playing, following) {
+                // loaded before anything changes, one try and no waiting, so a bad file can't stall the fade
+                let path = &self.tracks[following as usize].path;
+                let level = self.level_of(following);
+                let loaded = !self.failures.is_unplayable(path) && match self.fading_player.as_mut() {
+                    Some(fading) => {
+                        fading.set_level(level);
+                        fading.load(path, 0).is_ok()
+                    },
+                    None => false
+                };
+                if loaded {
+                    self.bookmarks.finished(&self.tracks[self.currently_playing as usize].path);
+                    if let Some(fading) = self.fading_player.as_mut() { std::mem::swap(&mut self.player, fading); }
+                    self.currently_playing = self.play_order.advance(&self.tracks).unwrap_or(following);
+                    log!("player-control", "crossfading into track {} over {}s", self.currently_playing, left);
+                    self.track_loaded();
+                    self.play_counted = false;
+                    self.last_time_pos = self.resume_at(self.currently_playing);
+                    self.crossfade = Some((Instant::now(), left.max(0.1), 0));
+                } else {
+                    // the end of the track brings it in, with the usual retries and skipping
+                    log!("player-control", "track {} won't load for a crossfade, changing track plainly instead", following);
+                    self.crossfade_refused = true;
+                }
+            }
+        }
+        if let (Some((started, secs, last_volume)), Some(fading)) = (self.crossfade, self.fading_player.as_mut()) {
+            let t = (started.elapsed().as_secs_f32() / secs).min(1.0);
+            if t >= 1.0 || fading.poll_event().is_some() {
+                fading.stop();
+                self.player.set_volume(self.current_volume);
+                self.crossfade = None;
+            } else {
+                let v = (self.current_volume as f32 * t).round() as u32;
+                if v != last_volume {
+                    self.player.set_volume(v);
+                    fading.set_volume(self.current_volume - v);
+                    self.crossfade = Some((started, secs, v));
+                }
+            }
+        }
+        // sleep timer, fading out over its last stretch
+        let sleep_left = self.sleep_timer.remaining(&self.tracks, self.currently_playing, self.last_time_pos, self.length_of_song);
+        match sleep_left {
+            Some(left) if self.sleep_timer.runs_out(left) => fall_asleep = true,
+            Some(left) if left < sleep_timer::FADE_SECS && !self.currently_paused => {
+                let v = (self.current_volume as f32 * left / sleep_timer::FADE_SECS).round() as u32;
+                if self.fade != Some((self.currently_playing, v)) {
+                    self.player.set_volume(v);
+                    self.fade = Some((self.currently_playing, v));
+                }
+            },
+            _ => {}
+        }
+        if fall_asleep {
+            log!("player-control", "sleep timer ran out, pausing");
+            self.sleep_timer.set(SleepSetting::Off);
+            if !self.currently_paused {
+                self.player.toggle_pause();
+                self.set_currently_paused(true);
+                self.paused_silent = self.paused_silent || self.fade.is_some();
+            }
+            self.fade = None;
+        }
+        let label = self.sleep_timer.label(sleep_left);
+        if label != self.sleep_label {
+            self.replies.send(ControlMsg::SLEEP(label.clone()));
+            self.sleep_label = label;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::sync::Once;
+    use std::sync::mpsc::{self, Receiver};
+
+    use super::PlayerControl;
+    use crate::{Track, ControlMsg};
+    use crate::bookmarks::Bookmarks;
+    use crate::history::PlayHistory;
+    use crate::player::fake::FakeBackend;
+    use crate::player_state::PlayerState;
+    use crate::read_config::{self, BuckConfig};
+    use crate::replay_gain::{ReplayGain, Leveled};
+    use crate::speeds::Speeds;
+
+    // state files and the log go to a scratch directory, emptied at the start of each run
+    fn use_temp_root() {
+        static ROOT: Once = Once::new();
+        ROOT.call_once(|| {
+            let dir = std::env::temp_dir().join("buck-player-control-tests");
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            read_config::set_root(dir);
+        });
+    }
+
+    fn config() -> BuckConfig {
+        serde_json::from_str(r#"{
+            "ui": { "width": 600, "height": 800, "scale": 1.0 },
+            "documents_dir": "/tmp",
+            "music_dirs": [],
+            "event_paths": { "pointer": "" },
+            "disable_scrub": false
+        }"#).unwrap()
+    }
+
+    // two albums of two tracks
+    fn library() -> Vec<Track> {
+        [("A", 1, 1), ("A", 1, 2), ("B", 2, 1), ("B", 2, 2)].iter().map(|&(album, album_number, track)| Track {
+            path: PathBuf::from(format!("/music/{}/{}.mp3", album, track)),
+            title: format!("{} {}", album, track),
+            artist: String::from("Artist"),
+            album: String::from(album),
+            track,
+            disc: 1,
+            year: 0,
+            album_artist: String::new(),
+            duration: 60.0,
+            album_number,
+            album_track: track,
+            added: 0,
+            replay_gain: ReplayGain::default(),
+            unplayable: false
+        }).collect()
+    }
+
+    // started and unpaused, on the first track
+    fn start_playing(tracks: &[Track], backend: FakeBackend) -> (PlayerControl, Receiver<ControlMsg>) {
+        use_temp_root();
+        let (tx, rx) = mpsc::channel();
+        let state = PlayerState { track: Some(tracks[0].path.clone()), paused: false, ..PlayerState::default() };
+        let config = config();
+        let mut control = PlayerControl::new(tracks.to_vec(), &config, Leveled::new(Box::new(backend)), None, tx, state, Bookmarks::load(&config), Speeds::load(), PlayHistory::default());
+        control.start();
+        (control, rx)
+    }
+
+    fn new_tracks(rx: &Receiver<ControlMsg>) -> Vec<u32> {
+        rx.try_iter().filter_map(|m| match m { ControlMsg::NEWTRACK(t) => Some(t), _ => None }).collect()
+    }
+
+    // ticks until the current track changes, or gives up after a minute of fake playback
+    fn play_until_change(control: &mut PlayerControl) {
+        let from = control.currently_playing;
+        for _ in 0..600 {
+            control.tick();
+            if control.currently_playing != from {
+                return;
+            }
+        }
+    }
+
+    #[test]
+    fn next_and_prev_step_through_the_catalog() {
+        let tracks = library();
+        let (mut control, rx) = start_playing(&tracks, FakeBackend::new(60.0));
+        control.handle(ControlMsg::NEXT());
+        control.handle(ControlMsg::NEXT());
+        assert_eq!(control.currently_playing, 2);
+        control.handle(ControlMsg::PREV());
+        assert_eq!(control.currently_playing, 1);
+        assert!(!control.currently_paused);
+        assert_eq!(new_tracks(&rx), vec![0, 1, 2, 1]);
+    }
+
+    #[test]
+    fn end_of_track_moves_on_to_the_next_one() {
+        let tracks = library();
+        let (mut control, rx) = start_playing(&tracks, FakeBackend::new(60.0).with_length(&tracks[0].path, 1.0));
+        play_until_change(&mut control);
+        assert_eq!(control.currently_playing, 1);
+        assert!(!control.currently_paused);
+        assert_eq!(control.length_of_song, 60.0);
+        assert_eq!(new_tracks(&rx), vec![0, 1]);
+    }
+
+    #[test]
+    fn unplayable_tracks_are_skipped_and_remembered() {
+        let tracks = library();
+        let (mut control, rx) = start_playing(&tracks, FakeBackend::new(60.0).with_unplayable(&tracks[1].path));
+        control.handle(ControlMsg::NEXT());
+        assert_eq!(control.currently_playing, 2);
+        assert!(control.failures.is_unplayable(&tracks[1].path));
+        // from then on it is passed over without trying it again
+        control.handle(ControlMsg::SETTRACK(0));
+        control.handle(ControlMsg::NEXT());
+        assert_eq!(control.currently_playing, 2);
+        assert_eq!(new_tracks(&rx), vec![0, 2, 0, 2]);
+    }
+
+    #[test]
+    fn seeks_and_volume_resume_playback() {
+        let tracks = library();
+        let (mut control, rx) = start_playing(&tracks, FakeBackend::new(60.0));
+        control.handle(ControlMsg::PAUSE());
+        assert!(control.currently_paused);
+        control.handle(ControlMsg::SEEK_FORWARD(5.0));
+        assert!(!control.currently_paused);
+        control.tick();
+        assert!(control.last_time_pos > 5.0);
+        control.handle(ControlMsg::PAUSE());
+        control.handle(ControlMsg::SETVOL(40));
+        assert!(!control.currently_paused);
+        assert!(rx.try_iter().any(|m| matches!(m, ControlMsg::VOL(40))));
+    }
+}
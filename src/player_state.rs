@@ -0,0 +1,62 @@
+// player_state.rs
+// Where playback was, so a restart picks up from the same spot
+
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use serde::{Serialize, Deserialize};
+
+use crate::error;
+use crate::play_order::PlayModes;
+use crate::read_config::root;
+use crate::utils::atomic_write::write_atomic;
+
+const STATE_FILE: &str = "state.json";
+// while playing, the position is written this often (anything else as soon as it changes)
+pub const SAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct PlayerState {
+    // by path, indexes move around whenever music is added
+    pub track: Option<PathBuf>,
+    pub position: f32,
+    pub volume: u32,
+    pub paused: bool,
+    pub modes: PlayModes,
+    // name of the equalizer preset
+    pub equalizer: String
+}
+
+impl Default for PlayerState {
+    fn default() -> PlayerState {
+        PlayerState { track: None, position: 0.0, volume: 60, paused: true, modes: PlayModes::default(), equalizer: String::from("Flat") }
+    }
+}
+
+impl PlayerState {
+    pub fn load() -> PlayerState {
+        match fs::read_to_string(root(STATE_FILE)) {
+            Ok(s) => match serde_json::from_str(&s) {
+                Ok(state) => state,
+                Err(e) => {
+                    error!("player_state", "ignoring unreadable state: {:?}", e);
+                    PlayerState::default()
+                }
+            },
+            Err(_) => PlayerState::default()
+        }
+    }
+    pub fn save(&self) {
+        let result = serde_json::to_vec(self).map_err(std::io::Error::from).and_then(|data| write_atomic(&root(STATE_FILE), &data));
+        if let Err(e) = result {
+            error!("player_state", "could not write state: {:?}", e);
+        }
+    }
+    // everything but the position, which changes all the time
+    pub fn same_settings(&self, other: &PlayerState) -> bool {
+        self.track == other.track && self.volume == other.volume && self.paused == other.paused && self.modes == other.modes && self.equalizer == other.equalizer
+    }
+}
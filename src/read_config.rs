@@ -6,8 +6,14 @@ use std::fmt;
 use std::fs::{self, OpenOptions};
 use std::io::{Write, BufReader, Read};
 use std::process::{exit};
+use std::sync::OnceLock;
 
 use crate::{log, error, result};
+use crate::track_code::TrackNumbering;
+use crate::equalizer::EqPreset;
+use crate::replay_gain::ReplayGainMode;
+use crate::player::PlayerKind;
+use crate::player::sink::NativeOutput;
 
 use serde::{Serialize, Deserialize};
 use serde_json::Result;
@@ -24,21 +30,154 @@ pub struct BuckUIConfig {
     pub scale: f32
 }
 
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct BuckTocConfig {
+    pub dpi: Option<f64>,
+    pub font_scale: f64,
+    pub numbering: TrackNumbering,
+    pub recently_added_days: u32,
+    pub most_played: usize,
+    pub album_covers: bool,
+    pub cover_size_mm: f64,
+    pub cover_px: u32,
+    pub cover_grey_levels: u8
+}
+
+impl Default for BuckTocConfig {
+    fn default() -> BuckTocConfig {
+        BuckTocConfig { dpi: None, font_scale: 1.0, numbering: TrackNumbering::Index, recently_added_days: 0, most_played: 0, album_covers: false, cover_size_mm: 16.0, cover_px: 96, cover_grey_levels: 16 }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct BuckHttpConfig {
+    pub enabled: bool,
+    pub port: u16
+}
+
+impl Default for BuckHttpConfig {
+    fn default() -> BuckHttpConfig {
+        BuckHttpConfig { enabled: false, port: 8765 }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct BuckNativeConfig {
+    pub output: NativeOutput,
+    pub device: String,
+    pub wav_path: String
+}
+
+impl Default for BuckNativeConfig {
+    fn default() -> BuckNativeConfig {
+        let device = if cfg!(feature = "btonly") { "bluealsa" } else { "default" };
+        BuckNativeConfig { output: NativeOutput::Alsa, device: device.to_string(), wav_path: "/tmp/buck-native.wav".to_string() }
+    }
+}
+
+// resume positions are kept for files at least min_length_mins long (0 turns that off), and for anything in spoken_word_dirs
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct BuckBookmarksConfig {
+    pub min_length_mins: u32,
+    pub spoken_word_dirs: Vec<String>
+}
+
+impl Default for BuckBookmarksConfig {
+    fn default() -> BuckBookmarksConfig {
+        BuckBookmarksConfig { min_length_mins: 20, spoken_word_dirs: Vec::new() }
+    }
+}
+
+// seconds per tap of the seek buttons; holding one keeps seeking, each step hold_growth times the last, up to hold_max_secs
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct BuckSeekConfig {
+    pub forward_secs: f32,
+    pub backward_secs: f32,
+    pub hold_growth: f32,
+    pub hold_max_secs: f32
+}
+
+impl Default for BuckSeekConfig {
+    fn default() -> BuckSeekConfig {
+        BuckSeekConfig { forward_secs: 5.0, backward_secs: 5.0, hold_growth: 2.0, hold_max_secs: 120.0 }
+    }
+}
+
+// presets of the user's own, on top of the built-in ones (a preset with a built-in name replaces it)
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct BuckEqualizerConfig {
+    pub presets: Vec<EqPreset>
+}
+
+// volume normalisation from ReplayGain tags, preamp_db is added to every gain
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct BuckReplayGainConfig {
+    pub mode: ReplayGainMode,
+    pub preamp_db: f32
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct BuckConfig {
     pub ui: BuckUIConfig,
     pub documents_dir: String,
     pub music_dirs: Vec<String>,
     pub event_paths: BuckEventPaths,
-    pub disable_scrub: bool
+    pub disable_scrub: bool,
+    #[serde(default)]
+    pub toc: BuckTocConfig,
+    #[serde(default)]
+    pub fallback_fonts: Vec<String>,
+    #[serde(default)]
+    pub http: BuckHttpConfig,
+    #[serde(default)]
+    pub player: PlayerKind,
+    #[serde(default)]
+    pub native: BuckNativeConfig,
+    #[serde(default)]
+    pub bookmarks: BuckBookmarksConfig,
+    #[serde(default)]
+    pub seek: BuckSeekConfig,
+    // seconds two tracks overlap by when playback moves on to another album, 0 for none
+    #[serde(default)]
+    pub crossfade_secs: f32,
+    #[serde(default)]
+    pub equalizer: BuckEqualizerConfig,
+    #[serde(default)]
+    pub replay_gain: BuckReplayGainConfig
+}
+
+// pixel density of a device with a UI scale of 1.0 (the Kindle Touch)
+const BASE_DPI: f64 = 167.0;
+
+impl BuckConfig {
+    pub fn screen_dpi(&self) -> f64 {
+        self.toc.dpi.unwrap_or(BASE_DPI * self.ui.scale as f64)
+    }
+}
+
+// set when running on a PC against a mounted device, where Buck's folder is not the executable's own
+static ROOT_OVERRIDE: OnceLock<PathBuf> = OnceLock::new();
+
+pub fn set_root(dir: PathBuf) {
+    ROOT_OVERRIDE.set(dir).expect("root already set");
 }
 
 pub fn root(s: &str) -> PathBuf {
-    result!(env::current_exe()).parent().unwrap().join(s)
+    match ROOT_OVERRIDE.get() {
+        Some(dir) => dir.join(s),
+        None => result!(env::current_exe()).parent().unwrap().join(s)
+    }
 }
 
 pub fn read_config() -> BuckConfig {
-    let config_path = result!(env::current_exe()).parent().unwrap().join("config.json");
+    let config_path = root("config.json");
     let mut config_file = result!(OpenOptions::new().write(false).read(true).create(false).open(config_path));
     let mut config_str = String::new();
     config_file.read_to_string(&mut config_str);
@@ -0,0 +1,220 @@
+// replay_gain.rs
+// Loudness normalisation from ReplayGain and R128 tags, applied by scaling the volume handed to the backend
+
+use std::path::Path;
+
+use serde::{Serialize, Deserialize};
+
+use crate::equalizer::Bands;
+use crate::play_order::ShuffleMode;
+use crate::player::{PlayerBackend, PlayerEvent, LoadError};
+
+// gains in dB relative to the ReplayGain reference level, peaks as linear sample amplitude (1.0 is full scale)
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Debug)]
+pub struct ReplayGain {
+    pub track_gain: Option<f32>,
+    pub track_peak: Option<f32>,
+    pub album_gain: Option<f32>,
+    pub album_peak: Option<f32>
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum ReplayGainMode {
+    Off,
+    Track,
+    Album,
+    // album gain while albums play through, track gain while shuffling tracks
+    Auto
+}
+
+impl Default for ReplayGainMode {
+    fn default() -> ReplayGainMode {
+        ReplayGainMode::Off
+    }
+}
+
+// "-6.52 dB" -> -6.52
+fn parse_number(value: &str) -> Option<f32> {
+    value.trim().trim_end_matches("dB").trim_end_matches("db").trim().parse().ok()
+}
+
+impl ReplayGain {
+    // picks up any of the REPLAYGAIN_* or R128_* tags (TXXX descriptions, Vorbis comment names), others are ignored
+    pub fn read_tag(&mut self, key: &str, value: &str) {
+        let key = key.to_ascii_uppercase();
+        match key.as_str() {
+            "REPLAYGAIN_TRACK_GAIN" => self.track_gain = parse_number(value),
+            "REPLAYGAIN_TRACK_PEAK" => self.track_peak = parse_number(value),
+            "REPLAYGAIN_ALBUM_GAIN" => self.album_gain = parse_number(value),
+            "REPLAYGAIN_ALBUM_PEAK" => self.album_peak = parse_number(value),
+            // Q7.8 fixed point, relative to -23 LUFS where ReplayGain aims at -18
+            "R128_TRACK_GAIN" => self.track_gain = self.track_gain.or(value.trim().parse::<i32>().ok().map(|q| q as f32 / 256.0 + 5.0)),
+            "R128_ALBUM_GAIN" => self.album_gain = self.album_gain.or(value.trim().parse::<i32>().ok().map(|q| q as f32 / 256.0 + 5.0)),
+            _ => {}
+        }
+    }
+    // volume multiplier, never so much that the peak would clip
+    pub fn level(&self, mode: ReplayGainMode, shuffle: ShuffleMode, preamp_db: f32) -> f32 {
+        let album = match mode {
+            ReplayGainMode::Off => return 1.0,
+            ReplayGainMode::Track => false,
+            ReplayGainMode::Album => true,
+            ReplayGainMode::Auto => !matches!(shuffle, ShuffleMode::Tracks | ShuffleMode::Artist)
+        };
+        // whichever of the two is there when the preferred one is missing
+        let (gain, peak) = if album {
+            (self.album_gain.or(self.track_gain), self.album_peak.or(self.track_peak))
+        } else {
+            (self.track_gain.or(self.album_gain), self.track_peak.or(self.album_peak))
+        };
+        let gain = match gain {
+            Some(g) => g,
+            None => return 1.0
+        };
+        let level = 10f32.powf((gain + preamp_db) / 20.0);
+        match peak {
+            Some(p) if p > 0.0 => level.min(1.0 / p),
+            _ => level
+        }
+    }
+}
+
+// the backend the control thread talks to, volumes go through to the one underneath scaled by the current track's level
+pub struct Leveled {
+    inner: Box<dyn PlayerBackend + Send>,
+    volume: u32,
+    level: f32
+}
+
+impl Leveled {
+    pub fn new(inner: Box<dyn PlayerBackend + Send>) -> Leveled {
+        Leveled { inner, volume: 0, level: 1.0 }
+    }
+    fn scaled(&self, volume: u32) -> u32 {
+        (volume as f32 * self.level).round() as u32
+    }
+    // for the next load, or for the playing track with refresh_volume()
+    pub fn set_level(&mut self, level: f32) {
+        self.level = level;
+    }
+    // like set_volume, this resumes playback
+    pub fn refresh_volume(&mut self) {
+        let v = self.scaled(self.volume);
+        self.inner.set_volume(v);
+    }
+}
+
+impl PlayerBackend for Leveled {
+    fn load(&mut self, path: &Path, volume: u32) -> Result<(), LoadError> {
+        self.volume = volume;
+        let v = self.scaled(volume);
+        self.inner.load(path, v)
+    }
+    fn preload(&mut self, path: &Path) -> bool {
+        self.inner.preload(path)
+    }
+    fn clear_preload(&mut self) {
+        self.inner.clear_preload()
+    }
+    fn toggle_pause(&mut self) {
+        self.inner.toggle_pause()
+    }
+    fn seek_relative(&mut self, secs: f32) {
+        self.inner.seek_relative(secs)
+    }
+    fn seek_absolute(&mut self, secs: f32) {
+        self.inner.seek_absolute(secs)
+    }
+    fn set_volume(&mut self, volume: u32) {
+        self.volume = volume;
+        let v = self.scaled(volume);
+        self.inner.set_volume(v)
+    }
+    fn set_speed(&mut self, speed: f32) -> f32 {
+        self.inner.set_speed(speed)
+    }
+    fn set_equalizer(&mut self, bands: &Bands) {
+        self.inner.set_equalizer(bands)
+    }
+    fn position(&mut self) -> Option<f32> {
+        self.inner.position()
+    }
+    fn length(&self) -> f32 {
+        self.inner.length()
+    }
+    fn poll_event(&mut self) -> Option<PlayerEvent> {
+        self.inner.poll_event()
+    }
+    fn stop(&mut self) {
+        self.inner.stop()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ReplayGain, ReplayGainMode};
+    use crate::play_order::ShuffleMode;
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 0.001
+    }
+
+    fn tagged(tags: &[(&str, &str)]) -> ReplayGain {
+        let mut gain = ReplayGain::default();
+        for (key, value) in tags {
+            gain.read_tag(key, value);
+        }
+        gain
+    }
+
+    #[test]
+    fn r128_gains_move_to_the_replaygain_reference() {
+        // -1280 is -5 dB from -23 LUFS, which is 0 dB from ReplayGain's -18
+        let gain = tagged(&[("R128_TRACK_GAIN", "-1280"), ("r128_album_gain", "256")]);
+        assert!(close(gain.track_gain.unwrap(), 0.0));
+        assert!(close(gain.album_gain.unwrap(), 6.0));
+        // a ReplayGain tag wins over R128 whichever comes first
+        let gain = tagged(&[("R128_TRACK_GAIN", "256"), ("REPLAYGAIN_TRACK_GAIN", "-6.52 dB")]);
+        assert!(close(gain.track_gain.unwrap(), -6.52));
+        let gain = tagged(&[("REPLAYGAIN_TRACK_GAIN", "-6.52 dB"), ("R128_TRACK_GAIN", "256")]);
+        assert!(close(gain.track_gain.unwrap(), -6.52));
+    }
+
+    #[test]
+    fn gains_in_db_become_volume_multipliers() {
+        let gain = tagged(&[("REPLAYGAIN_TRACK_GAIN", "-6.02 dB"), ("REPLAYGAIN_ALBUM_GAIN", "-20 dB")]);
+        assert!(close(gain.level(ReplayGainMode::Track, ShuffleMode::Off, 0.0), 0.5));
+        assert!(close(gain.level(ReplayGainMode::Album, ShuffleMode::Off, 0.0), 0.1));
+        // the preamp adds to the gain
+        assert!(close(gain.level(ReplayGainMode::Album, ShuffleMode::Off, 20.0), 1.0));
+        // auto follows albums unless tracks are shuffled
+        assert!(close(gain.level(ReplayGainMode::Auto, ShuffleMode::Albums, 0.0), 0.1));
+        assert!(close(gain.level(ReplayGainMode::Auto, ShuffleMode::Tracks, 0.0), 0.5));
+        assert_eq!(gain.level(ReplayGainMode::Off, ShuffleMode::Off, 0.0), 1.0);
+    }
+
+    #[test]
+    fn missing_tags_leave_the_volume_alone() {
+        let gain = ReplayGain::default();
+        assert_eq!(gain.level(ReplayGainMode::Track, ShuffleMode::Off, 0.0), 1.0);
+        assert_eq!(gain.level(ReplayGainMode::Album, ShuffleMode::Off, 6.0), 1.0);
+        // one kind stands in for the other
+        let gain = tagged(&[("REPLAYGAIN_TRACK_GAIN", "-6.02"), ("R128_ALBUM_GAIN", "not a number")]);
+        assert_eq!(gain.album_gain, None);
+        assert!(close(gain.level(ReplayGainMode::Album, ShuffleMode::Off, 0.0), 0.5));
+    }
+
+    #[test]
+    fn peaks_stop_a_boost_from_clipping() {
+        // +6 dB would double the volume, a peak of 0.8 only leaves room for 1.25x
+        let gain = tagged(&[("REPLAYGAIN_TRACK_GAIN", "+6.02 dB"), ("REPLAYGAIN_TRACK_PEAK", "0.8")]);
+        assert!(close(gain.level(ReplayGainMode::Track, ShuffleMode::Off, 0.0), 1.25));
+        // cuts are left as they are
+        let gain = tagged(&[("REPLAYGAIN_TRACK_GAIN", "-6.02 dB"), ("REPLAYGAIN_TRACK_PEAK", "0.8")]);
+        assert!(close(gain.level(ReplayGainMode::Track, ShuffleMode::Off, 0.0), 0.5));
+        // a zero peak is ignored rather than divided by
+        let gain = tagged(&[("REPLAYGAIN_TRACK_GAIN", "+6.02 dB"), ("REPLAYGAIN_TRACK_PEAK", "0")]);
+        assert!(close(gain.level(ReplayGainMode::Track, ShuffleMode::Off, 0.0), 2.0));
+    }
+}
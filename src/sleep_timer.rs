@@ -0,0 +1,166 @@
+// sleep_timer.rs
+// Stops playback after a while, or at the end of the current track or album, fading out first
+
+use std::time::{Duration, Instant};
+
+use crate::Track;
+
+// the volume goes down to nothing over this last stretch
+pub const FADE_SECS: f32 = 30.0;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SleepSetting {
+    Off,
+    Minutes(u32),
+    EndOfTrack,
+    EndOfAlbum
+}
+
+impl SleepSetting {
+    // the steps of the button on the player screen
+    pub fn cycle(self) -> SleepSetting {
+        match self {
+            SleepSetting::Off => SleepSetting::Minutes(15),
+            SleepSetting::Minutes(m) if m < 30 => SleepSetting::Minutes(30),
+            SleepSetting::Minutes(m) if m < 60 => SleepSetting::Minutes(60),
+            SleepSetting::Minutes(_) => SleepSetting::EndOfTrack,
+            SleepSetting::EndOfTrack => SleepSetting::EndOfAlbum,
+            SleepSetting::EndOfAlbum => SleepSetting::Off
+        }
+    }
+    // "30", "track", "album" or "off"
+    pub fn parse(s: &str) -> Option<SleepSetting> {
+        match s.trim() {
+            "off" | "0" => Some(SleepSetting::Off),
+            "track" => Some(SleepSetting::EndOfTrack),
+            "album" => Some(SleepSetting::EndOfAlbum),
+            m => m.parse().ok().map(SleepSetting::Minutes)
+        }
+    }
+}
+
+pub struct SleepTimer {
+    setting: SleepSetting,
+    deadline: Option<Instant>
+}
+
+impl SleepTimer {
+    pub fn new() -> SleepTimer {
+        SleepTimer { setting: SleepSetting::Off, deadline: None }
+    }
+    pub fn set(&mut self, setting: SleepSetting) {
+        self.setting = setting;
+        self.deadline = match setting {
+            SleepSetting::Minutes(m) => Some(Instant::now() + Duration::from_secs(m as u64 * 60)),
+            _ => None
+        };
+    }
+    pub fn setting(&self) -> SleepSetting {
+        self.setting
+    }
+    // seconds of playback left, None while the timer is off
+    pub fn remaining(&self, tracks: &[Track], current: u32, position: f32, length: f32) -> Option<f32> {
+        let track_left = (length - position).max(0.0);
+        match self.setting {
+            SleepSetting::Off => None,
+            SleepSetting::Minutes(_) => self.deadline.map(|d| d.saturating_duration_since(Instant::now()).as_secs_f32()),
+            SleepSetting::EndOfTrack => Some(track_left),
+            // the rest of the album, in catalog order
+            SleepSetting::EndOfAlbum => {
+                let album = tracks[current as usize].album_number;
+                let rest: f32 = tracks[current as usize + 1..].iter().take_while(|t| t.album_number == album).map(|t| t.duration).sum();
+                Some(track_left + rest)
+            }
+        }
+    }
+    // minutes stop playback where it is, track and album timers wait for the change of track
+    // so the next one gets loaded silent
+    pub fn runs_out(&self, remaining: f32) -> bool {
+        remaining <= 0.0 && matches!(self.setting, SleepSetting::Minutes(_))
+    }
+    // checked when playback moves from one track to the next by itself
+    pub fn ends_at_change(&self, tracks: &[Track], from: u32, to: u32) -> bool {
+        match self.setting {
+            SleepSetting::EndOfTrack => true,
+            SleepSetting::EndOfAlbum => tracks[from as usize].album_number != tracks[to as usize].album_number,
+            _ => false
+        }
+    }
+    pub fn label(&self, remaining: Option<f32>) -> String {
+        let minutes = remaining.map(|r| (r / 60.0).ceil() as u32).unwrap_or(0);
+        match self.setting {
+            SleepSetting::Off => String::from("off"),
+            SleepSetting::Minutes(_) => format!("{}m", minutes),
+            SleepSetting::EndOfTrack => format!("track {}m", minutes),
+            SleepSetting::EndOfAlbum => format!("album {}m", minutes)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::{SleepTimer, SleepSetting};
+    use crate::Track;
+    use crate::replay_gain::ReplayGain;
+
+    // two albums of two tracks, a minute each
+    fn tracks() -> Vec<Track> {
+        [(1, 1), (1, 2), (2, 1), (2, 2)].iter().map(|&(album_number, track)| Track {
+            path: PathBuf::from(format!("/music/{}/{}.mp3", album_number, track)),
+            title: format!("Track {}", track),
+            artist: String::from("Artist"),
+            album: format!("Album {}", album_number),
+            track,
+            disc: 1,
+            year: 0,
+            album_artist: String::new(),
+            duration: 60.0,
+            album_number,
+            album_track: track,
+            added: 0,
+            replay_gain: ReplayGain::default(),
+            unplayable: false
+        }).collect()
+    }
+
+    #[test]
+    fn minutes_run_out_mid_track() {
+        let mut timer = SleepTimer::new();
+        timer.set(SleepSetting::Minutes(15));
+        assert!(timer.runs_out(0.0));
+        assert!(!timer.runs_out(10.0));
+        assert!(!timer.ends_at_change(&tracks(), 0, 1));
+    }
+
+    #[test]
+    fn end_of_track_waits_for_the_next_one() {
+        let tracks = tracks();
+        let mut timer = SleepTimer::new();
+        timer.set(SleepSetting::EndOfTrack);
+        assert_eq!(timer.remaining(&tracks, 0, 20.0, 60.0), Some(40.0));
+        // nothing left of the track, but the next one is what gets paused on
+        assert!(!timer.runs_out(0.0));
+        assert!(timer.ends_at_change(&tracks, 0, 1));
+    }
+
+    #[test]
+    fn end_of_album_waits_for_the_next_album() {
+        let tracks = tracks();
+        let mut timer = SleepTimer::new();
+        timer.set(SleepSetting::EndOfAlbum);
+        assert_eq!(timer.remaining(&tracks, 0, 20.0, 60.0), Some(100.0));
+        assert!(!timer.runs_out(0.0));
+        assert!(!timer.ends_at_change(&tracks, 0, 1));
+        assert!(timer.ends_at_change(&tracks, 1, 2));
+    }
+
+    #[test]
+    fn off_never_stops_playback() {
+        let timer = SleepTimer::new();
+        assert_eq!(timer.remaining(&tracks(), 0, 20.0, 60.0), None);
+        assert!(!timer.runs_out(0.0));
+        assert!(!timer.ends_at_change(&tracks(), 1, 2));
+    }
+}
@@ -0,0 +1,130 @@
+// speeds.rs
+// Playback speeds, remembered for a single track or a whole collection (the folder a track is in)
+
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::{log, error};
+use crate::read_config::root;
+use crate::utils::atomic_write::write_atomic;
+
+const SPEEDS_FILE: &str = "speeds.json";
+pub const MIN_SPEED: f32 = 0.75;
+pub const MAX_SPEED: f32 = 2.0;
+// the steps of the button on the player screen
+const STEPS: [f32; 6] = [0.75, 1.0, 1.25, 1.5, 1.75, 2.0];
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SpeedScope {
+    Track,
+    Collection
+}
+
+pub fn next_step(speed: f32) -> f32 {
+    STEPS.iter().copied().find(|s| *s > speed + 0.01).unwrap_or(STEPS[0])
+}
+
+// "1.5" for the collection, "track 1.5" for the current track only
+pub fn parse(s: &str) -> Option<(f32, SpeedScope)> {
+    let s = s.trim();
+    let (scope, value) = match s.strip_prefix("track") {
+        Some(rest) => (SpeedScope::Track, rest),
+        None => (SpeedScope::Collection, s)
+    };
+    let speed: f32 = value.trim().trim_end_matches('x').parse().ok()?;
+    if speed >= MIN_SPEED && speed <= MAX_SPEED { Some((speed, scope)) } else { None }
+}
+
+pub fn label(speed: f32) -> String {
+    format!("{}x", (speed * 100.0).round() / 100.0)
+}
+
+pub struct Speeds {
+    // keyed by the path of a track or of a folder
+    speeds: HashMap<PathBuf, f32>
+}
+
+impl Speeds {
+    pub fn load() -> Speeds {
+        let speeds = match fs::read_to_string(root(SPEEDS_FILE)) {
+            Ok(s) => serde_json::from_str(&s).unwrap_or_else(|e| {
+                error!("speeds", "ignoring unreadable speeds: {:?}", e);
+                HashMap::new()
+            }),
+            Err(_) => HashMap::new()
+        };
+        Speeds { speeds }
+    }
+    fn save(&self) {
+        let result = serde_json::to_vec(&self.speeds).map_err(std::io::Error::from).and_then(|data| write_atomic(&root(SPEEDS_FILE), &data));
+        if let Err(e) = result {
+            error!("speeds", "could not write speeds: {:?}", e);
+        }
+    }
+    // the track's own speed, else the closest folder above it that has one
+    pub fn speed_for(&self, path: &Path) -> f32 {
+        path.ancestors().find_map(|p| self.speeds.get(p)).copied().unwrap_or(1.0)
+    }
+    pub fn set(&mut self, path: &Path, speed: f32, scope: SpeedScope) {
+        let key = match scope {
+            SpeedScope::Track => path,
+            SpeedScope::Collection => path.parent().unwrap_or(path)
+        };
+        log!("speeds", "{} at {}", key.display(), speed);
+        if scope == SpeedScope::Collection {
+            // the collection's speed takes over from whatever its tracks had
+            self.speeds.retain(|p, _| !p.starts_with(key));
+        }
+        // normal speed needs no entry unless it overrides a folder further up
+        if speed == 1.0 && !key.ancestors().skip(1).any(|p| self.speeds.contains_key(p)) {
+            self.speeds.remove(key);
+        } else {
+            self.speeds.insert(key.to_path_buf(), speed);
+        }
+        self.save();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::path::{Path, PathBuf};
+
+    use super::{Speeds, SpeedScope};
+    use crate::player::PlayerBackend;
+    use crate::player::fake::FakeBackend;
+
+    #[test]
+    fn the_button_steps_up_and_wraps_around() {
+        assert_eq!(super::next_step(1.0), 1.25);
+        assert_eq!(super::next_step(1.1), 1.25);
+        assert_eq!(super::next_step(2.0), 0.75);
+    }
+
+    #[test]
+    fn parse_takes_a_scope_and_stays_in_range() {
+        assert_eq!(super::parse("1.5"), Some((1.5, SpeedScope::Collection)));
+        assert_eq!(super::parse("track 1.25x"), Some((1.25, SpeedScope::Track)));
+        assert_eq!(super::parse("3"), None);
+        assert_eq!(super::parse("fast"), None);
+    }
+
+    #[test]
+    fn a_track_speed_wins_over_its_collection() {
+        let mut speeds = HashMap::new();
+        speeds.insert(PathBuf::from("/books/novel"), 1.5);
+        speeds.insert(PathBuf::from("/books/novel/03.mp3"), 1.25);
+        let speeds = Speeds { speeds };
+        assert_eq!(speeds.speed_for(Path::new("/books/novel/01.mp3")), 1.5);
+        assert_eq!(speeds.speed_for(Path::new("/books/novel/03.mp3")), 1.25);
+        assert_eq!(speeds.speed_for(Path::new("/music/song.mp3")), 1.0);
+    }
+
+    #[test]
+    fn backends_without_speed_control_report_normal_speed() {
+        let mut backend = FakeBackend::new(60.0);
+        assert_eq!(backend.set_speed(1.5), 1.0);
+    }
+}
@@ -0,0 +1,131 @@
+// text_fonts.rs
+// Font fallback for text the main font (Bookerly) has no glyphs for (CJK, Cyrillic, symbols, ...)
+
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use rusttype::{Font, FontCollection, Scale, point};
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+
+use crate::{log, error};
+use crate::read_config::{root, BuckConfig};
+
+pub const PRIMARY_FONT: &str = "assets/Bookerly-Regular.ttf";
+
+static TEXT_FONTS: OnceLock<TextFonts> = OnceLock::new();
+
+pub struct TextFont {
+    pub path: PathBuf,
+    font: Font<'static>
+}
+
+// fonts[0] is always the primary font, the configured fallbacks follow in order of preference
+pub struct TextFonts {
+    fonts: Vec<TextFont>,
+    dpi: f64
+}
+
+fn load_font(path: &Path) -> Option<Font<'static>> {
+    let data = match fs::read(path) {
+        Ok(d) => d,
+        Err(e) => {
+            error!("text_fonts", "could not read font {}: {:?}", path.display(), e);
+            return None;
+        }
+    };
+    // .ttc collections are accepted here, but the PDF renderer only handles single fonts
+    match FontCollection::from_bytes(data).and_then(|c| c.font_at(0)) {
+        Ok(f) => Some(f),
+        Err(e) => {
+            error!("text_fonts", "could not parse font {}: {:?}", path.display(), e);
+            None
+        }
+    }
+}
+
+impl TextFonts {
+    pub fn load(primary: PathBuf, fallbacks: &[String], dpi: f64) -> TextFonts {
+        // everything is measured against the primary font, there is nothing to fall back from without it
+        let font = match load_font(&primary) {
+            Some(font) => font,
+            None => {
+                error!("fatal", "primary font {} is missing", primary.display());
+                std::process::exit(1)
+            }
+        };
+        let mut fonts = vec![TextFont { path: primary, font }];
+        for f in fallbacks {
+            let path = if Path::new(f).is_absolute() { PathBuf::from(f) } else { root("assets").join(f) };
+            if let Some(font) = load_font(&path) {
+                log!("text_fonts", "loaded fallback font {}", path.display());
+                fonts.push(TextFont { path, font });
+            }
+        }
+        TextFonts { fonts, dpi }
+    }
+    pub fn fallbacks(&self) -> &[TextFont] {
+        &self.fonts[1..]
+    }
+    pub fn path(&self, font: usize) -> &Path {
+        &self.fonts[font].path
+    }
+    fn font_for(&self, c: char) -> usize {
+        self.fonts.iter().position(|f| f.font.glyph(c).id().0 != 0).unwrap_or(0)
+    }
+    // splits text into runs that can each be drawn with a single font (index into fonts, 0 = primary)
+    pub fn runs<'a>(&self, text: &'a str) -> Vec<(usize, &'a str)> {
+        let mut runs = Vec::new();
+        let mut start = 0;
+        let mut current: Option<usize> = None;
+        for (i, c) in text.char_indices() {
+            // spaces and combining marks stick to whatever font came before them
+            let font = if c.is_whitespace() || c.width() == Some(0) { current.unwrap_or(0) } else { self.font_for(c) };
+            match current {
+                Some(f) if f != font => {
+                    runs.push((f, &text[start..i]));
+                    start = i;
+                    current = Some(font);
+                },
+                None => current = Some(font),
+                _ => {}
+            }
+        }
+        if let Some(f) = current {
+            runs.push((f, &text[start..]));
+        }
+        runs
+    }
+    pub fn needs_fallback(&self, text: &str) -> bool {
+        self.runs(text).iter().any(|(f, _)| *f != 0)
+    }
+    // fbink's font sizes are in points
+    pub fn pt_to_px(&self, pt: u32) -> f32 {
+        (pt as f64 * self.dpi / 72.0) as f32
+    }
+    pub fn run_width_px(&self, font: usize, text: &str, px: f32) -> f32 {
+        let f = &self.fonts[font].font;
+        let scale = Scale::uniform(px);
+        f.layout(text, scale, point(0.0, 0.0))
+            .last()
+            .map(|g| g.position().x + g.unpositioned().h_metrics().advance_width)
+            .unwrap_or(0.0)
+    }
+}
+
+pub fn init(config: &BuckConfig) {
+    let fonts = TextFonts::load(root(PRIMARY_FONT), &config.fallback_fonts, config.screen_dpi());
+    if TEXT_FONTS.set(fonts).is_err() {
+        error!("text_fonts", "fonts already initialised");
+    }
+}
+
+pub fn get() -> &'static TextFonts {
+    TEXT_FONTS.get().expect("text_fonts::init was not called")
+}
+
+// columns taken up on screen, wide (CJK) characters count double unlike with str::len
+pub fn display_width(s: &str) -> usize {
+    UnicodeWidthStr::width(s)
+}
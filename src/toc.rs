@@ -1,35 +1,205 @@
 // toc.rs
 // Table of Contents generator
 
-use std::{fs::OpenOptions, io::Write, path::PathBuf};
+use std::{fs::{self, OpenOptions}, io::Write, path::{Path, PathBuf}, time::{SystemTime, UNIX_EPOCH}};
 
-use genpdf::{self, Alignment, Mm, Element, style::{Style, Color}, elements::{Paragraph, TableLayout}, Margins};
-use textwrap::wrap;
+use genpdf::{self, Alignment, Mm, Element, style::{Style, Color}, elements::{Paragraph, TableLayout, LinearLayout, Image}, fonts::{FontData, FontFamily, Font}, Margins};
+use id3::Tag;
+use image::DynamicImage;
+use textwrap::WordSeparator;
 
 use crate::{Track, log, result, error, process_runner::{quick_write, quick_run}};
-use crate::read_config::root;
+use crate::album_art;
+use crate::track_code::{TrackCode, TrackNumbering};
+use crate::read_config::{root, BuckConfig};
+use crate::text_fonts::{self, display_width};
+use crate::utils::atomic_write::write_atomic;
+use crate::history::PlayHistory;
 
-pub fn gentoc(tracks: &Vec<Track>, pdf_output_path: PathBuf) {
+pub const TOC_FILE_NAME: &str = "Buck - Table of Contents.pdf";
+const FINGERPRINT_FILE: &str = "toc-fingerprint.txt";
+
+// the layout below is designed for an A4 page, and gets scaled down to the device's screen
+const REFERENCE_PAGE_WIDTH_MM: f64 = 210.0;
+// A4 minus the page decorator's left and right margins
+const CONTENT_WIDTH_MM: f64 = 190.0;
+
+struct PageGeometry {
+    width_mm: f64,
+    height_mm: f64,
+    factor: f64,
+    font_factor: f64
+}
+
+impl PageGeometry {
+    fn new(config: &BuckConfig) -> PageGeometry {
+        let dpi = config.screen_dpi();
+        let width_mm = config.ui.width as f64 / dpi * 25.4;
+        let height_mm = config.ui.height as f64 / dpi * 25.4;
+        let factor = width_mm / REFERENCE_PAGE_WIDTH_MM;
+        PageGeometry { width_mm, height_mm, factor, font_factor: factor * config.toc.font_scale }
+    }
+    fn mm(&self, v: f64) -> Mm {
+        Mm::from((v * self.factor) as f32)
+    }
+    fn margins(&self, t: f64, r: f64, b: f64, l: f64) -> Margins {
+        Margins::trbl(self.mm(t), self.mm(r), self.mm(b), self.mm(l))
+    }
+    fn pt(&self, v: u8) -> u8 {
+        (v as f64 * self.font_factor).round().clamp(1.0, u8::MAX as f64) as u8
+    }
+}
+
+// m:ss, or h:mm:ss for anything an hour or longer
+pub fn format_duration(secs: f32) -> String {
+    let total = secs.round() as u64;
+    if total >= 3600 {
+        format!("{}:{:02}:{:02}", total / 3600, (total % 3600) / 60, total % 60)
+    } else {
+        format!("{}:{:02}", total / 60, total % 60)
+    }
+}
+
+// number of tracks and total runtime of the album starting at tracks[start]
+pub fn album_summary(tracks: &[Track], start: usize) -> (usize, f32) {
+    let album = &tracks[start].album;
+    tracks[start..].iter()
+        .take_while(|t| &t.album == album)
+        .fold((0, 0.0), |(count, runtime), t| (count + 1, runtime + t.duration))
+}
+
+// "Recently added" lists at most this many albums
+const MAX_RECENT_ALBUMS: usize = 20;
+
+// the optional sections at the top of the T.O.C., as indices into the tracks vector so they keep their usual codes
+pub struct TocSections {
+    // first track of each album, newest album first
+    pub recently_added: Vec<usize>,
+    // with their play counts, most played first
+    pub most_played: Vec<(usize, u32)>
+}
+
+impl TocSections {
+    pub fn new(tracks: &[Track], config: &BuckConfig, history: &PlayHistory) -> TocSections {
+        let mut recently_added: Vec<(usize, u64)> = Vec::new();
+        if config.toc.recently_added_days > 0 {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+            let cutoff = now.saturating_sub(config.toc.recently_added_days as u64 * 24 * 3600);
+            let mut i = 0;
+            while i < tracks.len() {
+                let (track_count, _) = album_summary(tracks, i);
+                let added = tracks[i..i + track_count].iter().map(|t| t.added).max().unwrap_or(0);
+                if added >= cutoff {
+                    recently_added.push((i, added));
+                }
+                i += track_count;
+            }
+            recently_added.sort_by(|a, b| b.1.cmp(&a.1));
+            recently_added.truncate(MAX_RECENT_ALBUMS);
+        }
+        let mut most_played: Vec<(usize, u32)> = Vec::new();
+        if config.toc.most_played > 0 {
+            most_played = tracks.iter().enumerate().map(|(i, t)| (i, history.count(&t.path))).filter(|(_, c)| *c > 0).collect();
+            most_played.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+            most_played.truncate(config.toc.most_played);
+        }
+        TocSections { recently_added: recently_added.into_iter().map(|(i, _)| i).collect(), most_played }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.recently_added.is_empty() && self.most_played.is_empty()
+    }
+}
+
+// FNV-1a over everything that ends up in the document, stable across builds so that a T.O.C.
+// rendered on a PC is recognised as current on the device
+fn fingerprint(tracks: &[Track], sections: &TocSections, config: &BuckConfig) -> String {
+    let mut h: u64 = 0xcbf29ce484222325;
+    let mut feed = |s: &str| {
+        for b in s.bytes().chain(std::iter::once(0)) {
+            h ^= b as u64;
+            h = h.wrapping_mul(0x100000001b3);
+        }
+    };
+    feed(&serde_json::to_string(&config.toc).unwrap_or_default());
+    feed(&format!("{}x{}@{}", config.ui.width, config.ui.height, config.ui.scale));
+    feed(&config.fallback_fonts.join("\n"));
+    for t in tracks {
+        feed(&t.title);
+        feed(&t.artist);
+        feed(&t.album);
+        feed(&t.album_artist);
+        feed(&format!("{:.0} {} {}", t.duration, t.album_number, t.album_track));
+    }
+    // only which tracks are in Most played, not their counts or order, so playing music doesn't
+    // force a slow re-render on every boot, the section gets redone once a track enters or leaves it
+    let mut most_played: Vec<usize> = sections.most_played.iter().map(|&(i, _)| i).collect();
+    most_played.sort();
+    feed(&format!("{:?} {:?}", sections.recently_added, most_played));
+    format!("{:016x}", h)
+}
+
+// true if the T.O.C. at pdf_output_path was rendered from this exact catalog and config
+pub fn is_current(tracks: &[Track], sections: &TocSections, pdf_output_path: &Path, config: &BuckConfig) -> bool {
+    pdf_output_path.exists() && fs::read_to_string(root(FINGERPRINT_FILE))
+        .map(|s| s.trim() == fingerprint(tracks, sections, config))
+        .unwrap_or(false)
+}
+
+pub fn gentoc(tracks: &Vec<Track>, sections: &TocSections, pdf_output_path: PathBuf, config: &BuckConfig) {
 
     log!("gentoc", "starting...");
 
+    let toc_config = &config.toc;
+    let page = PageGeometry::new(config);
+    log!("gentoc", "page size {:.1}x{:.1}mm, scale factor {:.2}", page.width_mm, page.height_mm, page.factor);
+
     let font_family = result!(genpdf::fonts::from_files(root("assets"), "Bookerly", None));
 
     let mut doc = genpdf::Document::new(font_family);
 
     doc.set_title("Buck - Table of Contents");
+    doc.set_paper_size((Mm::from(page.width_mm as f32), Mm::from(page.height_mm as f32)));
 
     let mut decorator = genpdf::SimplePageDecorator::new();
-    decorator.set_margins(Margins::trbl(20 as i8, 10 as i8, 10 as i8, 10 as i8));
+    decorator.set_margins(page.margins(20.0, 10.0, 10.0, 10.0));
     doc.set_page_decorator(decorator);
 
     doc.set_minimal_conformance();
     doc.set_line_spacing(2.0);
 
+    let text_fonts = text_fonts::get();
+    // one entry per fallback, so run font indices line up even when one of them can't be used here
+    let fallback_families: Vec<Option<FontFamily<Font>>> = text_fonts.fallbacks().iter().map(|f| {
+        match FontData::load(&f.path, None) {
+            Ok(fd) => Some(doc.add_font_family(FontFamily { regular: fd.clone(), bold: fd.clone(), italic: fd.clone(), bold_italic: fd })),
+            Err(e) => {
+                error!("gentoc", "could not use {} in the T.O.C.: {:?}", f.path.display(), e);
+                None
+            }
+        }
+    }).collect();
+
+    // user supplied text (titles, artists), switching fonts for glyphs Bookerly doesn't have
+    let text_paragraph = |s: &str, style: Style| -> Paragraph {
+        let mut p = genpdf::elements::Paragraph::default();
+        for (font, run) in text_fonts.runs(s) {
+            let run_style = match fallback_families.get(font.wrapping_sub(1)) {
+                Some(Some(family)) => style.with_font_family(*family),
+                _ => style
+            };
+            // genpdf only breaks lines at spaces or between the strings it is given, so each
+            // Unicode line break opportunity (between CJK characters, after symbols, ...) gets its own string
+            for word in WordSeparator::UnicodeBreakProperties.find_words(run) {
+                p.push_styled(format!("{}{}", word.word, word.whitespace), run_style);
+            }
+        }
+        p
+    };
+
     let mut header = genpdf::elements::Paragraph::default();
-    header.push_styled("Table of Contents", Style::new().with_font_size(40));
+    header.push_styled("Table of Contents", Style::new().with_font_size(page.pt(40)));
     header.set_alignment(Alignment::Left);
-    doc.push(header.padded(Margins::trbl(0 as i8, 0 as i8, 10 as i8, 0 as i8)));
+    doc.push(header.padded(page.margins(0.0, 0.0, 10.0, 0.0)));
 
     let calculate_optimal_layout = |text1len: usize, text2len: usize, text1min: usize, text2min: usize| -> Vec<usize> {
         let mut left = ((text1len as f64 / (text1len + text2len) as f64) * 100.0).floor() as usize;
@@ -45,61 +215,123 @@ pub fn gentoc(tracks: &Vec<Track>, pdf_output_path: PathBuf) {
         vec![left, right]
     };
 
-    let wrap_and_turn_into_a_paragraph = |s: &str, max_chars: u32, style: Style| -> Paragraph {
-        let lines = wrap(s, max_chars as usize);
-        let mut p = genpdf::elements::Paragraph::default();
-        for l in lines {
-            p.push_styled(l, style.clone());
+    let cover_weight = (toc_config.cover_size_mm / CONTENT_WIDTH_MM * 100.0).ceil() as usize + 1;
+    let cover_dpi = toc_config.cover_px as f64 / (toc_config.cover_size_mm * page.factor / 25.4);
+    let gen_album_cover = |t: &Track| -> Option<Image> {
+        let tag = Tag::read_from_path(&t.path).ok();
+        let cover = album_art::load_cover(tag.as_ref(), &t.path)?;
+        let thumb = album_art::eink_thumbnail(&cover, toc_config.cover_px, toc_config.cover_grey_levels);
+        match Image::from_dynamic_image(DynamicImage::ImageLuma8(thumb)) {
+            Ok(img) => Some(img.with_dpi(cover_dpi)),
+            Err(e) => {
+                error!("gentoc", "could not embed cover of {}: {:?}", t.album, e);
+                None
+            }
         }
-        p
     };
 
-    let gen_album_layout = |title: &str, artist: &str| -> TableLayout {
-        let mut title_p = genpdf::elements::Paragraph::default().styled_string(title, Style::new().with_font_size(24).italic());
+    let gen_album_layout = |title: &str, album_number: u32, artist: &str, (track_count, runtime): (usize, f32), cover: Option<Image>| -> TableLayout {
+        let title = match toc_config.numbering {
+            TrackNumbering::Index => title.to_string(),
+            TrackNumbering::Album => format!("{}. {}", album_number, title)
+        };
+        let mut title_p = text_paragraph(&title, Style::new().with_font_size(page.pt(24)).italic());
         title_p.set_alignment(Alignment::Left);
-        let mut artist_p = genpdf::elements::Paragraph::default().styled_string(artist, Style::new().with_font_size(22).with_color(Color::Rgb(59, 59, 59)));
+        let summary = format!("{} track{} · {}", track_count, if track_count == 1 { "" } else { "s" }, format_duration(runtime));
+        let mut summary_p = genpdf::elements::Paragraph::default().styled_string(summary, Style::new().with_font_size(page.pt(16)).with_color(Color::Rgb(117, 117, 117)));
+        summary_p.set_alignment(Alignment::Left);
+        let mut title_l = LinearLayout::vertical();
+        title_l.push(title_p);
+        title_l.push(summary_p);
+        let mut artist_p = text_paragraph(artist, Style::new().with_font_size(page.pt(22)).with_color(Color::Rgb(59, 59, 59)));
         artist_p.set_alignment(Alignment::Right);
-        let mut table = genpdf::elements::TableLayout::new(calculate_optimal_layout(title.len(), artist.len(), 40, 20));
-        table.row()
-            .element(title_p)
+        let mut l = calculate_optimal_layout(display_width(&title), display_width(artist), 40, 20);
+        if cover.is_some() {
+            l.insert(0, cover_weight);
+        }
+        let mut table = genpdf::elements::TableLayout::new(l);
+        let mut row = table.row();
+        if let Some(cover) = cover {
+            row = row.element(cover);
+        }
+        row.element(title_l)
             .element(artist_p)
             .push();
         table
     };
 
-    let gen_song_layout = |title: &str, artist: &str, pos: u32, first_track: &mut bool| -> TableLayout {
-        let mut pos_style = Style::new().with_font_size(20);
+    let gen_song_layout = |title: &str, artist: &str, duration: f32, code: TrackCode, first_track: &mut bool| -> TableLayout {
+        let mut pos_style = Style::new().with_font_size(page.pt(20));
         if *first_track {
-            pos_style = Style::new().with_font_size(21).italic();
+            pos_style = Style::new().with_font_size(page.pt(21)).italic();
             *first_track = false;
         }
-        let mut pos_p = genpdf::elements::Paragraph::default().styled_string(format!("{}. ", pos.to_string()), pos_style);
+        let label = match code {
+            TrackCode::Index(_) => format!("{}. ", code),
+            TrackCode::Album(_, _) => format!("{} ", code)
+        };
+        let mut pos_p = genpdf::elements::Paragraph::default().styled_string(&label, pos_style);
         pos_p.set_alignment(Alignment::Left);
-        let mut title_p = genpdf::elements::Paragraph::default().styled_string(title, Style::new().with_font_size(22));
+        let mut title_p = text_paragraph(title, Style::new().with_font_size(page.pt(22)));
         title_p.set_alignment(Alignment::Left);
-        let mut artist_p = genpdf::elements::Paragraph::default().styled_string(artist, Style::new().with_font_size(20).with_color(Color::Rgb(117, 117, 117)));
+        let mut artist_p = text_paragraph(artist, Style::new().with_font_size(page.pt(20)).with_color(Color::Rgb(117, 117, 117)));
         artist_p.set_alignment(Alignment::Right);
-        let mut l = calculate_optimal_layout(title.len(), artist.len(), 40, 25);
-        l.insert(0, 11.max(3 * (pos.to_string().len()) + 2));
+        let duration_str = if duration > 0.0 { format_duration(duration) } else { String::new() };
+        let mut duration_p = genpdf::elements::Paragraph::default().styled_string(duration_str, Style::new().with_font_size(page.pt(18)).with_color(Color::Rgb(117, 117, 117)));
+        duration_p.set_alignment(Alignment::Right);
+        let mut l = calculate_optimal_layout(display_width(title), display_width(artist), 40, 25);
+        l.insert(0, 11.max(3 * label.trim_end_matches(' ').len()));
+        l.push(12);
         let mut table = genpdf::elements::TableLayout::new(l);
         table.row()
             .element(pos_p)
             .element(title_p)
             .element(artist_p)
+            .element(duration_p)
             .push();
         table
     };
 
+    let album_cover = |t: &Track| -> Option<Image> {
+        if toc_config.album_covers { gen_album_cover(t) } else { None }
+    };
+
+    let gen_section_header = |title: &str| {
+        let mut p = genpdf::elements::Paragraph::default();
+        p.push_styled(title, Style::new().with_font_size(page.pt(30)));
+        p.set_alignment(Alignment::Left);
+        p.padded(page.margins(12.0, 0.0, 2.0, 0.0))
+    };
+
+    if !sections.recently_added.is_empty() {
+        doc.push(gen_section_header("Recently added"));
+        for &i in &sections.recently_added {
+            let t = &tracks[i];
+            let (_, runtime) = album_summary(tracks, i);
+            doc.push(gen_song_layout(&t.album, &t.album_artist, runtime, TrackCode::of(t, i, toc_config.numbering), &mut false).padded(page.margins(9.0, 0.0, 0.0, 0.0)));
+        }
+    }
+    if !sections.most_played.is_empty() {
+        doc.push(gen_section_header("Most played"));
+        for &(i, _) in &sections.most_played {
+            let t = &tracks[i];
+            doc.push(gen_song_layout(&t.title, &t.artist, t.duration, TrackCode::of(t, i, toc_config.numbering), &mut false).padded(page.margins(9.0, 0.0, 0.0, 0.0)));
+        }
+    }
+    if !sections.is_empty() {
+        doc.push(gen_section_header("All music"));
+    }
+
     let mut current_album = &tracks[0];
     let mut first_track = true;
-    doc.push(gen_album_layout(&current_album.album, &current_album.album_artist).padded(Margins::trbl(15 as i8, 0 as i8, 3 as i8, 0 as i8)));
+    doc.push(gen_album_layout(&current_album.album, current_album.album_number, &current_album.album_artist, album_summary(tracks, 0), album_cover(current_album)).padded(page.margins(15.0, 0.0, 3.0, 0.0)));
     for (t, i) in tracks.iter().zip(0..tracks.len()) {
         if current_album.album != t.album {
             first_track = true;
             current_album = &t;
-            doc.push(gen_album_layout(&current_album.album, &current_album.album_artist).padded(Margins::trbl(15 as i8, 0 as i8, 2 as i8, 0 as i8)));
+            doc.push(gen_album_layout(&current_album.album, current_album.album_number, &current_album.album_artist, album_summary(tracks, i), album_cover(current_album)).padded(page.margins(15.0, 0.0, 2.0, 0.0)));
         }
-        doc.push(gen_song_layout(&t.title, &t.artist, i as u32+1, &mut first_track).padded(Margins::trbl(9 as i8, 0 as i8, 0 as i8, 0 as i8)));
+        doc.push(gen_song_layout(&t.title, &t.artist, t.duration, TrackCode::of(t, i, toc_config.numbering), &mut first_track).padded(page.margins(9.0, 0.0, 0.0, 0.0)));
     }
 
     log!("gentoc", "starting render of T.O.C., this might take a while...");
@@ -110,6 +342,9 @@ pub fn gentoc(tracks: &Vec<Track>, pdf_output_path: PathBuf) {
     quick_write(3, "   (this might take a while)");
     println!("{}", pdf_output_path.display().to_string());
     doc.render_to_file(pdf_output_path).expect("failed to write T.O.C. to filesystem");
+    if let Err(e) = write_atomic(&root(FINGERPRINT_FILE), fingerprint(tracks, sections, config).as_bytes()) {
+        error!("gentoc", "could not save T.O.C. fingerprint: {:?}", e);
+    }
     quick_write(4, "* Done!");
 
     log!("gentoc", "write complete");
@@ -0,0 +1,69 @@
+// track_code.rs
+// Track addressing, either by position in the catalog or by album-number.track-number
+
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Serialize, Deserialize};
+
+use crate::Track;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum TrackNumbering {
+    Index,
+    Album
+}
+
+impl Default for TrackNumbering {
+    fn default() -> TrackNumbering {
+        TrackNumbering::Index
+    }
+}
+
+// both variants are 1-based, like everything shown to the user
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TrackCode {
+    Index(u32),
+    Album(u32, u32)
+}
+
+impl TrackCode {
+    pub fn of(t: &Track, index: usize, numbering: TrackNumbering) -> TrackCode {
+        match numbering {
+            TrackNumbering::Index => TrackCode::Index(index as u32 + 1),
+            TrackNumbering::Album => TrackCode::Album(t.album_number, t.album_track)
+        }
+    }
+    // position in the tracks vector, if the code points at an existing track
+    pub fn resolve(&self, tracks: &[Track]) -> Option<u32> {
+        match *self {
+            TrackCode::Index(i) => {
+                if i >= 1 && (i as usize) <= tracks.len() { Some(i - 1) } else { None }
+            },
+            TrackCode::Album(a, t) => {
+                tracks.iter().position(|x| x.album_number == a && x.album_track == t).map(|i| i as u32)
+            }
+        }
+    }
+}
+
+impl FromStr for TrackCode {
+    type Err = ();
+    fn from_str(s: &str) -> Result<TrackCode, ()> {
+        let s = s.trim();
+        match s.split_once('.') {
+            Some((a, t)) => Ok(TrackCode::Album(a.parse().map_err(|_| ())?, t.parse().map_err(|_| ())?)),
+            None => Ok(TrackCode::Index(s.parse().map_err(|_| ())?))
+        }
+    }
+}
+
+impl fmt::Display for TrackCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TrackCode::Index(i) => write!(f, "{}", i),
+            TrackCode::Album(a, t) => write!(f, "{}.{}", a, t)
+        }
+    }
+}
@@ -1 +1,2 @@
-pub mod elapsed;
\ No newline at end of file
+pub mod elapsed;
+pub mod atomic_write;
\ No newline at end of file